@@ -0,0 +1,197 @@
+/* Socket activation: listening sockets opened by a service manager
+ * and passed on as LISTEN_FDS, and telling it on NOTIFY_SOCKET when
+ * the server is ready or stopping
+ */
+use crate::config::{Address, Listen};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::socket::{getsockname, getsockopt, sockopt, SockType, SockaddrStorage};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use thiserror::Error;
+
+/* The first socket passed on, the others follow it */
+const LISTEN_FDS_START: RawFd = 3;
+
+#[derive(Debug, Error)]
+pub enum Error {
+	#[error("LISTEN_FDS \"{0}\" is not a number")]
+	Count(String),
+	#[error("socket {0}: {1}")]
+	Socket(RawFd, nix::Error),
+	#[error("socket {0} is not a listening stream socket")]
+	NotListening(RawFd),
+	#[error("NOTIFY_SOCKET {0}: {1}")]
+	Notify(String, std::io::Error),
+}
+
+#[derive(Debug)]
+pub struct Socket {
+	pub fd: OwnedFd,
+	pub name: Option<String>,
+	pub addr: Address,
+}
+
+impl std::fmt::Display for Socket {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match &self.name {
+			Some(name) => write!(f, "\"{name}\" ({})", self.addr),
+			None => write!(f, "{}", self.addr),
+		}
+	}
+}
+
+/* The sockets passed on to this process, none when it was not socket
+ * activated. The variables are removed either way, so that they do
+ * not reach the children
+ */
+pub fn sockets() -> Result<Vec<Socket>, Error> {
+	let pid = std::env::var("LISTEN_PID").ok();
+	let count = std::env::var("LISTEN_FDS").ok();
+	let names = std::env::var("LISTEN_FDNAMES").ok();
+	for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+		std::env::remove_var(var);
+	}
+	let (Some(pid), Some(count)) = (pid, count) else {
+		return Ok(Vec::new());
+	};
+	/* Meant for another process, which passed its environment on */
+	if pid.parse() != Ok(std::process::id()) {
+		return Ok(Vec::new());
+	}
+	let count: u16 = count.parse().map_err(|_| Error::Count(count))?;
+	let names: Vec<&str> = names.as_deref().map(|names| names.split(':').collect())
+		.unwrap_or_default();
+
+	let mut sockets = Vec::new();
+	for idx in 0..count {
+		let fd = LISTEN_FDS_START + RawFd::from(idx);
+		/* Also makes sure the descriptor is open before owning it */
+		fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(|err| Error::Socket(fd, err))?;
+		let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+		let addr = socket(&fd)?;
+		let name = names.get(usize::from(idx))
+			.filter(|name| !name.is_empty())
+			.map(|name| name.to_string());
+		sockets.push(Socket { fd, name, addr });
+	}
+	Ok(sockets)
+}
+
+/* Only listening TCP sockets and unix sockets bound to a path can
+ * stand in for a listener
+ */
+fn socket(fd: &OwnedFd) -> Result<Address, Error> {
+	let raw = fd.as_raw_fd();
+	let error = |err| Error::Socket(raw, err);
+	let listening = getsockopt(fd, sockopt::AcceptConn).map_err(error)?;
+	let stream = getsockopt(fd, sockopt::SockType).map_err(error)? == SockType::Stream;
+	let addr: SockaddrStorage = getsockname(raw).map_err(error)?;
+	let path = addr.as_unix_addr().and_then(|addr| addr.path());
+	let addr = match (addr.as_sockaddr_in(), addr.as_sockaddr_in6(), path) {
+		(Some(addr), _, _) => Address::Inet(SocketAddr::V4((*addr).into())),
+		(_, Some(addr), _) => Address::Inet(SocketAddr::V6((*addr).into())),
+		(_, _, Some(path)) => Address::Unix(path.to_string_lossy().into_owned()),
+		_ => return Err(Error::NotListening(raw)),
+	};
+	if !listening || !stream {
+		return Err(Error::NotListening(raw));
+	}
+	Ok(addr)
+}
+
+/* Gives each listener the socket named like it, of the same address
+ * family as a name may cover both, or else the one bound to its
+ * address. Returns the sockets by the address of their listener, and
+ * those no listener wanted
+ */
+pub fn adopt(sockets: Vec<Socket>, listeners: &[Listen])
+-> (HashMap<Address, OwnedFd>, Vec<Socket>) {
+	let family = |a: &Address, b: &Address| match (a, b) {
+		(Address::Inet(a), Address::Inet(b)) => a.is_ipv4() == b.is_ipv4(),
+		(Address::Unix(_), Address::Unix(_)) => true,
+		_ => false,
+	};
+	let mut sockets = sockets;
+	let mut adopted = HashMap::new();
+	for listen in listeners {
+		let named = sockets.iter().position(|socket| {
+			listen.name.is_some() && socket.name == listen.name && family(&socket.addr, &listen.addr)
+		});
+		let idx = named.or_else(|| sockets.iter().position(|socket| socket.addr == listen.addr));
+		if let Some(idx) = idx {
+			adopted.insert(listen.addr.clone(), sockets.remove(idx).fd);
+		}
+	}
+	(adopted, sockets)
+}
+
+/* The service manager's socket for status updates */
+pub struct Notify {
+	socket: UnixDatagram,
+}
+
+impl Notify {
+	/* None when the service manager does not ask for updates. The
+	 * socket is connected right away, before pledge() rules it out
+	 */
+	pub fn from_env() -> Result<Option<Self>, Error> {
+		let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+			return Ok(None);
+		};
+		std::env::remove_var("NOTIFY_SOCKET");
+		let path = path.to_string_lossy().into_owned();
+		let error = |err| Error::Notify(path.clone(), err);
+		let socket = UnixDatagram::unbound().map_err(error)?;
+		match path.strip_prefix('@') {
+			#[cfg(target_os = "linux")]
+			Some(name) => {
+				use std::os::linux::net::SocketAddrExt;
+				let addr = std::os::unix::net::SocketAddr::from_abstract_name(name).map_err(error)?;
+				socket.connect_addr(&addr).map_err(error)?;
+			}
+			_ => socket.connect(&path).map_err(error)?,
+		}
+		Ok(Some(Self { socket }))
+	}
+
+	pub fn send(&self, state: &str) -> std::io::Result<()> {
+		self.socket.send(state.as_bytes())?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[test]
+	fn adopt() {
+		let socket = |addr: &str, name: Option<&str>| {
+			let listener = std::net::TcpListener::bind(addr).unwrap();
+			let addr = listener.local_addr().unwrap();
+			Socket { fd: listener.into(), name: name.map(str::to_string), addr: Address::Inet(addr) }
+		};
+		let web = socket("127.0.0.1:0", Some("web"));
+		let other = socket("127.0.0.1:0", None);
+		let unused = socket("127.0.0.1:0", None);
+		let (web_addr, other_addr) = (web.addr.clone(), other.addr.clone());
+
+		let listen = |addr: Address, name: Option<&str>| Listen {
+			addr, tls: false, name: name.map(str::to_string), owner: None, group: None, mode: None,
+			proxy: None, forwarded: None,
+		};
+		let listeners = [
+			listen(Address::Inet("0.0.0.0:80".parse().unwrap()), Some("web")),
+			listen(Address::Inet("[::]:80".parse().unwrap()), Some("web")),
+			listen(other_addr.clone(), None),
+		];
+		let (adopted, left) = super::adopt(vec![unused, other, web], &listeners);
+		assert_eq!(adopted.len(), 2);
+		let addr = |fd: &OwnedFd| Address::Inet(std::net::TcpListener::from(fd.try_clone().unwrap())
+			.local_addr().unwrap());
+		assert_eq!(addr(&adopted[&listeners[0].addr]), web_addr);
+		assert_eq!(addr(&adopted[&other_addr]), other_addr);
+		assert_eq!(left.len(), 1);
+	}
+}
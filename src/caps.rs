@@ -0,0 +1,124 @@
+/* Capabilities for running the parent as an ordinary user, given
+ * what would otherwise take root as ambient capabilities
+ */
+use nix::libc;
+use thiserror::Error;
+
+pub const CAP_CHOWN: u32 = 0;
+pub const CAP_KILL: u32 = 5;
+pub const CAP_SETGID: u32 = 6;
+pub const CAP_SETUID: u32 = 7;
+pub const CAP_SETPCAP: u32 = 8;
+pub const CAP_NET_BIND_SERVICE: u32 = 10;
+pub const CAP_SYS_CHROOT: u32 = 18;
+
+const VERSION_3: u32 = 0x20080522;
+
+#[derive(Debug, Error)]
+pub enum Error {
+	#[error("capability {0} is missing")]
+	Missing(&'static str),
+	#[error("capabilities are left after dropping them")]
+	Left,
+	#[error("capabilities: {0}")]
+	Sys(#[from] std::io::Error),
+}
+
+#[repr(C)]
+struct Header {
+	version: u32,
+	pid: i32,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct Data {
+	effective: u32,
+	permitted: u32,
+	inheritable: u32,
+}
+
+fn get() -> std::io::Result<[Data; 2]> {
+	let mut header = Header { version: VERSION_3, pid: 0 };
+	let mut data = [Data::default(); 2];
+	if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+		return Err(std::io::Error::last_os_error());
+	}
+	Ok(data)
+}
+
+fn name(cap: u32) -> &'static str {
+	match cap {
+		CAP_CHOWN => "CAP_CHOWN",
+		CAP_KILL => "CAP_KILL",
+		CAP_SETGID => "CAP_SETGID",
+		CAP_SETUID => "CAP_SETUID",
+		CAP_SETPCAP => "CAP_SETPCAP",
+		CAP_NET_BIND_SERVICE => "CAP_NET_BIND_SERVICE",
+		CAP_SYS_CHROOT => "CAP_SYS_CHROOT",
+		_ => "unknown",
+	}
+}
+
+/* Every capability has to be effective now and ambient, so that the
+ * children get the ones they need for chroot() and setresuid()
+ */
+pub fn check(caps: &[u32]) -> Result<(), Error> {
+	let data = get()?;
+	for cap in caps {
+		let (idx, bit) = ((cap / 32) as usize, 1 << (cap % 32));
+		let ambient = unsafe {
+			libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_IS_SET, *cap as libc::c_ulong, 0, 0)
+		};
+		if data[idx].effective & bit == 0 || ambient != 1 {
+			return Err(Error::Missing(name(*cap)));
+		}
+	}
+	Ok(())
+}
+
+/* Drops every capability for good, executed programs cannot get
+ * any back from file capabilities either. Emptying the bounding set
+ * takes CAP_SETPCAP, so it goes before the rest
+ */
+pub fn drop_all() -> Result<(), Error> {
+	let sys = |ret: libc::c_int| match ret {
+		0 => Ok(()),
+		_ => Err(std::io::Error::last_os_error()),
+	};
+	sys(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+	sys(unsafe { libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0) })?;
+	for cap in 0.. {
+		if unsafe { libc::prctl(libc::PR_CAPBSET_READ, cap as libc::c_ulong, 0, 0, 0) } < 0 {
+			break;
+		}
+		sys(unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) })?;
+	}
+	let header = Header { version: VERSION_3, pid: 0 };
+	let data = [Data::default(); 2];
+	sys(unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) } as libc::c_int)?;
+	Ok(())
+}
+
+/* Checked after dropping them, rather than trusting it worked */
+pub fn verify_empty() -> Result<(), Error> {
+	if get()?.iter().any(|data| data.effective | data.permitted | data.inheritable != 0) {
+		return Err(Error::Left);
+	}
+	/* Ambient and bounding capabilities are asked for one by one
+	 * until the kernel does not know the capability
+	 */
+	for cap in 0.. {
+		let ambient = unsafe {
+			libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_IS_SET, cap as libc::c_ulong, 0, 0)
+		};
+		let bounding = unsafe { libc::prctl(libc::PR_CAPBSET_READ, cap as libc::c_ulong, 0, 0, 0) };
+		if ambient < 0 || bounding < 0 {
+			break;
+		}
+		if ambient == 1 || bounding == 1 {
+			return Err(Error::Left);
+		}
+	}
+	Ok(())
+}
@@ -1,13 +1,22 @@
-use crate::{fs, proc, http, mime};
+use crate::{fs, proc, proxy, http, mime, log, logger};
+use crate::metrics::Metrics;
+use crate::forwarded::{self, Origin};
+use crate::network::Network;
 use http::Content;
 use tokio_seqpacket::UnixSeqpacket;
 use tokio::net::{UnixStream, TcpStream};
 use tokio::signal::unix::{signal, SignalKind};
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, AsyncReadExt, BufStream, BufReader};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, AsyncReadExt, AsyncBufReadExt, BufStream, BufReader};
+use tokio_util::sync::CancellationToken;
 use serde::{Serialize, Deserialize};
 use num_enum::{TryFromPrimitive, IntoPrimitive};
 use proc::pledge;
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::net::{IpAddr, SocketAddr};
+use std::time::SystemTime;
+use tokio::time::Instant;
+use tokio::task::JoinSet;
 use std::fmt::Write;
 use thiserror::Error;
 
@@ -23,10 +32,10 @@ impl Content for tokio::fs::File {
 	}
 }
 
-struct Directory(String);
+struct Page(String);
 
 #[async_trait::async_trait]
-impl Content for Directory {
+impl Content for Page {
 	async fn len(&self) -> std::io::Result<usize> {
 		Ok(self.0.len())
 	}
@@ -36,80 +45,244 @@ impl Content for Directory {
 	}
 }
 
+/* servers holds the names of each server, in the same order as the
+ * filesystem process knows them.
+ */
 #[derive(Serialize, Deserialize)]
 pub struct ClientConfig {
+	pub listeners: Vec<ListenerConfig>,
+	pub servers: Vec<Vec<String>>,
+}
+
+/* The servers listening on a listener, the first one is the default */
+#[derive(Serialize, Deserialize)]
+pub struct ListenerConfig {
 	pub tls: bool,
+	pub unix: bool,
+	/* Proxies trusted to say who the client is */
+	pub forwarded: Option<Vec<Network>>,
+	pub servers: Vec<usize>,
+}
+
+impl ClientConfig {
+	fn server(&self, listener: usize, host: Option<&str>) -> usize {
+		let servers = &self.listeners[listener].servers;
+		host.and_then(|host| {
+			servers.iter().copied().find(|server| {
+				self.servers[*server].iter().any(|name| name.eq_ignore_ascii_case(host))
+			})
+		}).unwrap_or(servers[0])
+	}
 }
 
 pub async fn main() -> ! {
-	pledge("stdio recvfd sendfd", None).expect("pledge");
+	pledge("stdio recvfd sendfd unix", None).expect("pledge");
 	let parent = unsafe {
 		proc::Peer::get_parent()
 	};
 	
-	let (fs, mimedb) = {
-		let (_, (ffd, mfd)) = parent.recv_with_fds(&mut []).await.expect("no file descriptor");
-		let sock = UnixSeqpacket::try_from(ffd).unwrap();
-		let peer = Arc::new(proc::Peer::from_stream(sock));
+	/* The configuration, the slot this process counts its
+	 * connections in and its share of max connections come with the
+	 * mime database and the shared metrics, the channel to the
+	 * filesystem process follows as a message of its own
+	 */
+	let (config, slot, max_connections, mimedb, metrics) = {
+		let mut buf = vec![0u8; 65536];
+		let (len, (mfd, metrics)) = parent.recv_with_fds(&mut buf).await.expect("no file descriptors");
+		let (config, slot, max_connections): (ClientConfig, usize, usize) =
+			serde_cbor::from_slice(&buf[..len]).expect("serde");
+		let metrics = Metrics::map(&metrics).expect("metrics");
 		let mimedb = std::fs::File::from(mfd);
 		let mimedb = tokio::fs::File::from_std(mimedb);
 		let mut mimedb = BufReader::new(mimedb);
 		let mimedb = crate::mime::MimeDb::new(&mut mimedb).await.unwrap();
 		let mimedb = Arc::new(mimedb);
-		(peer, mimedb)
-	};
-
-	let config: ClientConfig = {
-		let mut buf = [0u8; 128];
-		let len = parent.socket().recv(&mut buf).await.unwrap();
-		serde_cbor::from_slice(&buf[..len]).expect("serde")
+		(Arc::new(config), slot, max_connections, mimedb, metrics)
 	};
+	let connections = Arc::new(Connections::new(metrics, slot));
+	let mut fs: Option<Arc<proc::Peer>> = None;
+	let mut logger: Option<Arc<proc::Peer>> = None;
 
-	let mut sigint = signal(SignalKind::interrupt()).expect("signal");
+	/* On SIGTERM the requests in progress are answered, but idle
+	 * keep-alive connections are closed and no new ones are taken
+	 */
+	let shutdown = CancellationToken::new();
+	{
+		let shutdown = shutdown.clone();
+		let mut sigint = signal(SignalKind::interrupt()).expect("signal");
+		let mut sigterm = signal(SignalKind::terminate()).expect("signal");
+		tokio::spawn(async move {
+			tokio::select! {
+				_ = sigint.recv() => {},
+				_ = sigterm.recv() => {},
+			}
+			shutdown.cancel();
+		});
+	}
 
-	loop {
+	/* Each connection is served by a task of its own, once there are
+	 * max_connections of them new ones wait until one finishes. The
+	 * parent's other messages are read all the same
+	 */
+	let mut tasks = JoinSet::new();
+	let mut waiting = VecDeque::new();
+	let mut retired = false;
+	/* Those still waiting are served once the parent retired this process */
+	while !retired || !waiting.is_empty() {
 		tokio::select! {
-			_ = sigint.recv() => { break },
-			stream = Accept::accept(&parent, config.tls) => {
-				let stream = match stream {
+			_ = shutdown.cancelled() => { break },
+			Some(_) = tasks.join_next() => {},
+			message = Message::recv(&parent), if !retired => {
+				let (message, fd) = match message {
+					Ok(message) => message,
+					/* The parent retired this process */
+					Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+						retired = true;
+						continue;
+					}
+					Err(err) => {
+						log::crit!("parent sent bad message: {err}");
+						std::process::exit(1);
+					}
+				};
+				let (listener, remote, proxy) = match message {
+					Message::Accept { listener, remote, proxy } => (listener, remote, proxy),
+					/* The first filesystem process, or one that was restarted */
+					Message::Filesystem => {
+						let sock = UnixSeqpacket::try_from(fd).unwrap();
+						fs = Some(Arc::new(proc::Peer::from_stream(sock)));
+						continue;
+					}
+					Message::Logger => {
+						let sock = UnixSeqpacket::try_from(fd).unwrap();
+						logger = Some(Arc::new(proc::Peer::from_stream(sock)));
+						continue;
+					}
+					/* One message a connection, closed after the last */
+					Message::Connections => {
+						let sock = UnixSeqpacket::try_from(fd).unwrap();
+						let list = connections.list();
+						tokio::spawn(async move {
+							for connection in list {
+								let buf = serde_cbor::to_vec(&connection).expect("serde");
+								if sock.send(&buf).await.is_err() {
+									break;
+								}
+							}
+						});
+						continue;
+					}
+				};
+				let stream = match Accept::new(fd, &config, listener) {
 					Ok(stream) => stream,
 					Err(err) => {
-						eprintln!("Parent sent bad stream: {err}");
+						log::crit!("parent sent bad stream: {err}");
 						std::process::exit(1);
 					}
 				};
-				let fs = fs.clone();
-				let mimedb = mimedb.clone();
-				match stream {
+				waiting.push_back((stream, listener, remote, proxy));
+			}
+		}
+
+		while tasks.len() < max_connections {
+			let Some((stream, listener, remote, proxy)) = waiting.pop_front() else {
+				break;
+			};
+			let Some(fs) = fs.clone() else {
+				log::error!("no filesystem process");
+				continue;
+			};
+			log::debug!("connection from {remote}");
+			let mimedb = mimedb.clone();
+			let logger = logger.clone();
+			let config = config.clone();
+			let shutdown = shutdown.clone();
+			let connections = connections.clone();
+			tasks.spawn(async move {
+				let result = match stream {
 					Accept::Tls(stream) => {
-						let mut client = BufStream::new(stream);
+						let mut client = BufStream::new(http::Counted::new(stream));
 						let Ok(byte) = client.read_u8().await else {
-							continue;
+							return;
 						};
 						let version: HttpVersion = byte.try_into().unwrap();
-						let mut client = Client {
-							version: version.into(), client, fs: &fs, mimedb: &mimedb
+						let remote = match decrypted(&mut client).await {
+							Ok(addr) => addr.map_or(remote, Remote::Inet),
+							Err(err) => {
+								log::error!("crypto process sent bad address: {err}");
+								return;
+							}
 						};
-						if let Err(err) = client.main().await {
-							eprintln!("client error: {err}");
-						}
+						let tracked = connections.open(listener, remote);
+						Client {
+							version, client, fs, logger, mimedb, config, listener, remote,
+							shutdown, tracked, metrics,
+						}.main().await
 					}
 					Accept::Plain(stream) => {
-						let client = BufStream::new(stream);
-						let mut client = Client {
-							version: HttpVersion::Unknown, client, fs: &fs, mimedb: &mimedb
+						let mut client = BufStream::new(http::Counted::new(stream));
+						let Some(remote) = proxied(&mut client, remote, proxy).await else {
+							return;
+						};
+						let tracked = connections.open(listener, remote);
+						Client {
+							version: HttpVersion::Unknown, client, fs, logger, mimedb,
+							config, listener, remote, shutdown, tracked, metrics,
+						}.main().await
+					}
+					Accept::Unix(stream) => {
+						let mut client = BufStream::new(http::Counted::new(stream));
+						let Some(remote) = proxied(&mut client, remote, proxy).await else {
+							return;
 						};
-						if let Err(err) = client.main().await {
-							eprintln!("client error: {err}");
-						}
+						let tracked = connections.open(listener, remote);
+						Client {
+							version: HttpVersion::Unknown, client, fs, logger, mimedb,
+							config, listener, remote, shutdown, tracked, metrics,
+						}.main().await
 					}
+				};
+				if let Err(err) = result {
+					log::info!("{remote}: {err}");
 				}
-			}
+			});
 		}
 	};
+
+	while tasks.join_next().await.is_some() {}
 	std::process::exit(0);
 }
 
+/* Takes the client's address from the PROXY protocol header the
+ * connection starts with, when it is to have one. None when there is
+ * no valid header, the connection is then dropped
+ */
+async fn proxied<T: AsyncRead + AsyncWrite + Unpin>(client: &mut BufStream<T>, remote: Remote,
+proxy: bool) -> Option<Remote> {
+	if !proxy {
+		return Some(remote);
+	}
+	match tokio::time::timeout(proxy::TIMEOUT, proxy::read(client)).await {
+		Ok(Ok(addr)) => Some(addr.map_or(remote, Remote::Inet)),
+		Ok(Err(err)) => {
+			log::info!("{remote}: {err}");
+			None
+		}
+		Err(_) => {
+			log::info!("{remote}: PROXY protocol: timed out");
+			None
+		}
+	}
+}
+
+/* The address the crypto process read from a PROXY protocol header */
+async fn decrypted<T: AsyncRead + Unpin>(client: &mut T) -> std::io::Result<Option<SocketAddr>> {
+	let len = client.read_u8().await?;
+	let mut buf = vec![0u8; len.into()];
+	client.read_exact(&mut buf).await?;
+	serde_cbor::from_slice(&buf).map_err(|_| std::io::ErrorKind::InvalidData.into())
+}
+
 #[repr(u8)]
 #[derive(TryFromPrimitive, IntoPrimitive)]
 pub enum HttpVersion {
@@ -127,18 +300,159 @@ impl From<http::Version> for HttpVersion {
 	}
 }
 
+/* Where a connection comes from. Peers on a unix socket have no
+ * address worth showing, they are told apart from others only
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Remote {
+	Inet(SocketAddr),
+	Unix,
+}
+
+impl Remote {
+	pub fn ip(&self) -> Option<IpAddr> {
+		match self {
+			Remote::Inet(addr) => Some(addr.ip()),
+			Remote::Unix => None,
+		}
+	}
+
+	/* Peers on unix sockets are trusted, only the system lets them in.
+	 * Without networks no other peer is
+	 */
+	pub fn trusted(&self, networks: &[Network]) -> bool {
+		match self {
+			Remote::Inet(addr) => networks.iter().any(|network| network.contains(addr.ip())),
+			Remote::Unix => true,
+		}
+	}
+
+	/* The address without the port, as access logs show it */
+	pub fn host(&self) -> String {
+		match self {
+			Remote::Inet(addr) => addr.ip().to_string(),
+			Remote::Unix => "unix:".to_string(),
+		}
+	}
+}
+
+impl std::fmt::Display for Remote {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Remote::Inet(addr) => write!(f, "{addr}"),
+			Remote::Unix => f.write_str("unix:"),
+		}
+	}
+}
+
+/* A connection being served, as the parent is told about it */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Connection {
+	pub listener: usize,
+	pub remote: Remote,
+	pub since: SystemTime,
+	pub requests: u64,
+}
+
+/* The connections of this process by a number of their own, the
+ * tasks serving them come and go on their own
+ */
+struct Connections {
+	open: Mutex<(u64, HashMap<u64, Connection>)>,
+	metrics: Metrics,
+	/* Where they are counted in the metrics */
+	slot: usize,
+}
+
+impl Connections {
+	fn new(metrics: Metrics, slot: usize) -> Self {
+		Self { open: Mutex::default(), metrics, slot }
+	}
+
+	fn open(self: &Arc<Self>, listener: usize, remote: Remote) -> Tracked {
+		self.metrics.opened(self.slot);
+		let mut guard = self.open.lock().unwrap();
+		let (next, open) = &mut *guard;
+		let id = *next;
+		*next += 1;
+		open.insert(id, Connection { listener, remote, since: SystemTime::now(), requests: 0 });
+		Tracked { id, connections: self.clone() }
+	}
+
+	fn list(&self) -> Vec<Connection> {
+		self.open.lock().unwrap().1.values().cloned().collect()
+	}
+}
+
+/* A connection that is forgotten once it is dropped */
+struct Tracked {
+	id: u64,
+	connections: Arc<Connections>,
+}
+
+impl Tracked {
+	/* Returns how many requests there were so far, this one included */
+	fn request(&self) -> u64 {
+		let mut guard = self.connections.open.lock().unwrap();
+		let connection = guard.1.get_mut(&self.id).expect("tracked connection");
+		connection.requests += 1;
+		connection.requests
+	}
+}
+
+impl Drop for Tracked {
+	fn drop(&mut self) {
+		self.connections.open.lock().unwrap().1.remove(&self.id);
+		self.connections.metrics.closed(self.connections.slot);
+	}
+}
+
+/* Messages from the parent, each one carries a file descriptor */
+#[derive(Serialize, Deserialize)]
+pub enum Message {
+	/* A connection accepted on the listener with this index */
+	Accept {
+		listener: usize,
+		remote: Remote,
+		/* The connection starts with a PROXY protocol header */
+		proxy: bool,
+	},
+	/* A channel to the filesystem process, replacing the previous one */
+	Filesystem,
+	/* A channel to the logger process, replacing the previous one */
+	Logger,
+	/* A channel to send the connections being served on */
+	Connections,
+}
+
+impl Message {
+	async fn recv(peer: &proc::Peer) -> std::io::Result<(Self, std::os::fd::OwnedFd)> {
+		let mut buf = [0u8; 128];
+		let (len, fd) = peer.recv_with_fd(&mut buf).await?;
+		let message = serde_cbor::from_slice(&buf[..len])
+			.map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidData))?;
+		Ok((message, fd))
+	}
+}
+
 pub enum Accept {
 	Tls(UnixStream),
 	Plain(TcpStream),
+	/* A connection on a unix socket listener */
+	Unix(UnixStream),
 }
 
 impl Accept {
-	async fn accept(peer: &proc::Peer, tls: bool) -> std::io::Result<Self> {
-		let (_, fd) = peer.recv_with_fd(&mut []).await?;
-		if tls {
+	fn new(fd: std::os::fd::OwnedFd, config: &ClientConfig, listener: usize) -> std::io::Result<Self> {
+		let listener = config.listeners.get(listener)
+			.ok_or(std::io::Error::from(std::io::ErrorKind::InvalidData))?;
+		if listener.tls || listener.unix {
 			let stream = std::os::unix::net::UnixStream::from(fd);
 			let stream = UnixStream::from_std(stream)?;
-			Ok(Self::Tls(stream))
+			match listener.tls {
+				true => Ok(Self::Tls(stream)),
+				false => Ok(Self::Unix(stream)),
+			}
 		}
 		else {
 			let stream = std::net::TcpStream::from(fd);
@@ -148,11 +462,27 @@ impl Accept {
 	}
 }
 
-struct Client<'a, T: AsyncRead + AsyncWrite> {
+/* A request being answered, with where it came from and when */
+struct Exchange<'a> {
+	server: usize,
+	request: &'a http::Request,
+	origin: Origin,
+	time: SystemTime,
+	start: Instant,
+}
+
+struct Client<T: AsyncRead + AsyncWrite> {
 	version: HttpVersion,
-	mimedb: &'a mime::MimeDb,
-	fs: &'a proc::Peer,
-	client: BufStream<T>,
+	config: Arc<ClientConfig>,
+	listener: usize,
+	remote: Remote,
+	shutdown: CancellationToken,
+	tracked: Tracked,
+	metrics: Metrics,
+	mimedb: Arc<mime::MimeDb>,
+	fs: Arc<proc::Peer>,
+	logger: Option<Arc<proc::Peer>>,
+	client: BufStream<http::Counted<T>>,
 }
 
 #[derive(Debug, Error)]
@@ -174,101 +504,171 @@ enum ClientError {
 	}
 }
 
-impl <T: Unpin + Send + AsyncRead + AsyncWrite> Client<'_, T> {
-	async fn resolve_path_under(&self, path: &str) -> std::io::Result<fs::OpenResponse> {
+impl <T: Unpin + Send + AsyncRead + AsyncWrite> Client<T> {
+	async fn resolve_path(&self, server: usize, path: &str, remote: Remote)
+	-> std::io::Result<fs::OpenResponse> {
 		let (mine, theirs) = UnixSeqpacket::pair()?;
-		let message = fs::RecvMessageClient::Open(path);
+		let message = fs::RecvMessageClient::Open { server, path, remote: remote.ip() };
 		let vec = serde_cbor::to_vec(&message).expect("serde");
+		let start = Instant::now();
 		self.fs.send_with_fd(theirs, &vec).await?;
 		let message = fs::OpenResponse::recv(&mine).await?;
+		self.metrics.latency(start.elapsed());
 		Ok(message)
 	}
-	async fn resolve_path(&self, path: &str) -> std::io::Result<fs::OpenResponse> {
-		if path.ends_with('/') {
-			let index = format!("{}/index.html", path);
-			let response = self.resolve_path_under(&index).await?;
-			if let fs::OpenResponse::File(info, file) = response {
-				Ok(fs::OpenResponse::File(info, file))
-			}
-			else {
-				self.resolve_path_under(path).await
+
+	async fn main(&mut self) -> Result<(), ClientError> {
+		while let Some(request) = self.get_request().await? {
+			let reused = self.tracked.request() > 1;
+			let exchange = Exchange {
+				server: self.config.server(self.listener, request.host()),
+				origin: self.origin(&request),
+				request: &request,
+				time: SystemTime::now(),
+				start: Instant::now(),
+			};
+			/* Requests that failed half way are logged as well */
+			let (code, bytes, result) = self.respond(&exchange).await;
+			self.metrics.request(request.method(), code, bytes, reused);
+			self.log(&exchange, code, bytes).await;
+			result?;
+			let keepalive = match self.version {
+				HttpVersion::One => false,
+				HttpVersion::OneOne => true,
+				HttpVersion::Unknown => request.version() == http::Version::OneOne,
+			};
+			if !keepalive || self.shutdown.is_cancelled() {
+				break;
 			}
 		}
-		else {
-			self.resolve_path_under(path).await
-		}
+		Ok(())
 	}
 
-	async fn main(&mut self) -> Result<(), ClientError> {
-		match self.version {
-			HttpVersion::One => {
-				Ok(self.run().await?)
-			}
-			HttpVersion::OneOne => {
-				loop {
-					self.run().await?;
-				}
-			}
-			HttpVersion::Unknown => {
-				let request = self.get_request().await?;
-				self.respond(&request).await?;
-				match request.version() {
-					http::Version::One => return Ok(()),
-					http::Version::OneOne => {
-						loop {
-							self.run().await?;
-						}
-					}
-				}
-			}
+	/* The peer, or the client it speaks for when it is a trusted proxy */
+	fn origin(&self, request: &http::Request) -> Origin {
+		let listener = &self.config.listeners[self.listener];
+		let peer = Origin { remote: self.remote, https: listener.tls };
+		match &listener.forwarded {
+			Some(trusted) => forwarded::origin(request, peer, trusted),
+			None => peer,
 		}
 	}
 
 	const KEEPALIVE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
-	async fn get_request(&mut self) -> Result<http::Request, ClientError> {
+	/* Returns None once the connection is closed, or when shutting
+	 * down before the next request has started to arrive
+	 */
+	async fn get_request(&mut self) -> Result<Option<http::Request>, ClientError> {
+		let client = &mut self.client;
+		let shutdown = &self.shutdown;
+		let pending = tokio::time::timeout(Self::KEEPALIVE_TIMEOUT, async {
+			tokio::select! {
+				buf = client.fill_buf() => buf.map(|buf| !buf.is_empty()),
+				_ = shutdown.cancelled() => Ok(false),
+			}
+		}).await??;
+		if !pending {
+			return Ok(None);
+		}
 		let request = tokio::time::timeout(Self::KEEPALIVE_TIMEOUT,
 			http::Request::read(&mut self.client)).await??;
-		Ok(request)
+		Ok(Some(request))
+	}
+
+	/* Access log records are best effort, a request is not failed
+	 * because the logger process went away
+	 */
+	async fn log(&self, exchange: &Exchange<'_>, code: http::ResponseCode, bytes: u64) {
+		let Some(logger) = &self.logger else {
+			return;
+		};
+		let request = exchange.request;
+		let record = logger::Record {
+			server: exchange.server,
+			remote: exchange.origin.remote,
+			https: exchange.origin.https,
+			time: exchange.time,
+			method: request.method().as_str().to_string(),
+			path: request.path().clone(),
+			version: request.version().as_str().to_string(),
+			status: code.status(),
+			bytes,
+			referer: request.header_line("Referer"),
+			user_agent: request.header_line("User-Agent"),
+			duration: exchange.start.elapsed(),
+		};
+		let buf = serde_cbor::to_vec(&record).expect("serde");
+		if let Err(err) = logger.socket().send(&buf).await {
+			log::warning!("access log: {err}");
+		}
 	}
 
-	async fn respond(&mut self, request: &http::Request) -> Result<(), ClientError> {
-		let response = self.resolve_path(request.path()).await.unwrap();
+	/* The status and the length of the body, or as much of it as was
+	 * sent when sending failed
+	 */
+	async fn respond(&mut self, exchange: &Exchange<'_>)
+	-> (http::ResponseCode, u64, Result<(), ClientError>) {
+		let request = exchange.request;
+		let response = self.resolve_path(exchange.server, request.path(), exchange.origin.remote)
+			.await;
+		let response = match response {
+			Ok(response) => response,
+			Err(err) => return (http::ResponseCode::InternalError, 0, Err(err.into())),
+		};
 		let head = request.method() == http::Method::HEAD;
 
-		match response {
+		let (code, (bytes, result)) = match response {
 			fs::OpenResponse::File(info, mut file) => {
 				let kind = self.mimedb.get(&info.name).unwrap_or("application/octet-stream");
 				let headers = [("Content-Type", kind)];
-				let mut response = http::Response::new(&mut file, &headers, head);
-				response.write(&mut self.client).await?;
+				(http::ResponseCode::Ok, Self::send(&mut self.client, &mut file, &headers, head).await)
 			}
 			fs::OpenResponse::Dir(dir) => {
 				let mut string = String::new();
 				string.push_str("<!DOCTYPE html>\n<html>\n<body>\n<pre>\n");
 				string.push_str("<a href=../>../</a>\n");
 				for file in dir {
-					write!(string, "<a href={0}>{0}</a>\n", file.name).unwrap();
+					writeln!(string, "<a href={0}>{0}</a>", file.name).unwrap();
 				}
 				string.push_str("</pre>\n</body>\n</html>\n");
-				let mut dir = Directory(string);
+				let mut dir = Page(string);
 				let headers = [("Content-Type", "text/html")];
-				let mut response = http::Response::new(&mut dir, &headers, head);
-				response.write(&mut self.client).await?;
+				(http::ResponseCode::Ok, Self::send(&mut self.client, &mut dir, &headers, head).await)
+			}
+			/* The text format for scrapers, a page for everyone else */
+			fs::OpenResponse::Metrics => {
+				let (page, kind) = match request.path().ends_with("/metrics") {
+					true => (self.metrics.text(), "text/plain; version=0.0.4"),
+					false => (self.metrics.html(), "text/html"),
+				};
+				let mut page = Page(page);
+				let headers = [("Content-Type", kind), ("Cache-Control", "no-store")];
+				(http::ResponseCode::Ok, Self::send(&mut self.client, &mut page, &headers, head).await)
 			}
 			fs::OpenResponse::FileError(error) => {
-				let mut response = http::ResponseCode::from(error);
-				let mut response = http::Response::new(&mut response, &[], head);
-				response.write(&mut self.client).await?;
+				let code = http::ResponseCode::from(error);
+				let mut content = code;
+				(code, Self::send(&mut self.client, &mut content, &[], head).await)
 			}
-		}
-		self.client.flush().await?;
-		Ok(())
+		};
+		(code, bytes, result.map_err(ClientError::from))
 	}
 
-	async fn run(&mut self) -> Result<(), ClientError> {
-		let request = self.get_request().await?;
-		self.respond(&request).await?;
-		Ok(())
+	/* Flushed before it counts as sent, when anything fails it is
+	 * what reached the peer that counts
+	 */
+	async fn send<C: Content + Send>(client: &mut BufStream<http::Counted<T>>, content: &mut C,
+	headers: &[(&str, &str)], head: bool) -> (u64, std::io::Result<()>) {
+		let start = client.get_ref().count();
+		let mut response = http::Response::new(content, headers, head);
+		let result = match response.write(client).await {
+			Ok(len) => client.flush().await.map(|()| len),
+			Err(err) => Err(err),
+		};
+		match result {
+			Ok(len) => (len, Ok(())),
+			Err(err) => (response.sent(client.get_ref().count() - start), Err(err)),
+		}
 	}
 }
 
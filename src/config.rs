@@ -0,0 +1,1228 @@
+use crate::{fs, log, logger, seccomp, ManagerConfig, TlsConfig};
+use crate::network::Network;
+use crate::logger::LoggerConfig;
+use crate::client::{ClientConfig, ListenerConfig};
+use crate::crypto::CryptoConfig;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use serde_derive::{Serialize, Deserialize};
+use std::fmt;
+use std::time::Duration;
+use thiserror::Error;
+
+pub const CONFIG_FILE: &str = "/etc/httpd.conf";
+
+const DEFAULT_USER: &str = "www";
+const DEFAULT_TYPES: &str = "/usr/share/misc/mime.types";
+const DEFAULT_CERTIFICATE: &str = "/etc/ssl/server.crt";
+const DEFAULT_KEY: &str = "/etc/ssl/private/server.key";
+const DEFAULT_ROOT: &str = "/htdocs";
+const DEFAULT_INDEX: &str = "index.html";
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+const DEFAULT_PIDFILE: &str = "/var/run/httpd.pid";
+const DEFAULT_CONTROL: &str = "/var/run/httpd.sock";
+const DEFAULT_CHROOT: &str = "/var/www";
+const DEFAULT_EMPTY: &str = "/var/empty";
+
+/* The default for prefork, taken once. Reloading parses in the
+ * chroot, where what it is read from is not there
+ */
+fn cpus() -> usize {
+	static CPUS: std::sync::OnceLock<usize> = std::sync::OnceLock::new();
+	*CPUS.get_or_init(|| std::thread::available_parallelism().map_or(1, |cpus| cpus.get()))
+}
+
+/* The first server is the default one, it answers requests whose
+ * Host header matches no other server
+ */
+#[derive(Debug, PartialEq)]
+pub struct Config {
+	pub user: String,
+	/* The primary group of the user when not given */
+	pub group: Option<String>,
+	/* The filesystem process is chrooted to the documents, the
+	 * others to an empty directory
+	 */
+	pub chroot: String,
+	pub empty: String,
+	pub types: String,
+	pub drain_timeout: Duration,
+	/* For the server as a whole, shared out between the clients */
+	pub max_connections: usize,
+	pub prefork: usize,
+	pub error_log: log::Target,
+	pub pidfile: String,
+	/* The parent's socket for httpd -p ctl, None with no control
+	 * socket
+	 */
+	pub control: Option<String>,
+	pub seccomp: seccomp::Mode,
+	pub servers: Vec<Server>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Server {
+	pub name: String,
+	pub aliases: Vec<String>,
+	pub listen: Vec<Listen>,
+	pub certificate: String,
+	pub key: String,
+	pub root: String,
+	pub index: Option<String>,
+	pub auto_index: bool,
+	pub log: Option<String>,
+	pub log_style: logger::Style,
+	pub locations: Vec<Location>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listen {
+	pub addr: Address,
+	pub tls: bool,
+	/* Picks the socket of this name under socket activation */
+	pub name: Option<String>,
+	/* Who may connect to a unix socket, left as created otherwise */
+	pub owner: Option<String>,
+	pub group: Option<String>,
+	pub mode: Option<u32>,
+	/* Connections start with a PROXY protocol header when they come
+	 * from one of these networks. There are none only on unix
+	 * sockets, whose peers are all trusted
+	 */
+	pub proxy: Option<Vec<Network>>,
+	/* Forwarded and X-Forwarded-* headers are believed from these
+	 * networks, the same goes for unix sockets
+	 */
+	pub forwarded: Option<Vec<Network>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Address {
+	Inet(SocketAddr),
+	Unix(String),
+}
+
+impl fmt::Display for Address {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Address::Inet(addr) => write!(f, "{addr}"),
+			Address::Unix(path) => f.write_str(path),
+		}
+	}
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Location {
+	pub path: String,
+	pub block: bool,
+	/* Only clients in these networks are let in, when there are any */
+	pub allow: Vec<Network>,
+	/* Answered with the server's metrics rather than files */
+	pub metrics: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+	#[error("{path}: {source}")]
+	Io {
+		path: String,
+		source: std::io::Error,
+	},
+	#[error("{path}:{source}")]
+	Parse {
+		path: String,
+		source: ParseError,
+	},
+}
+
+#[derive(Debug, Error, PartialEq)]
+#[error("{line}:{col}: {kind}")]
+pub struct ParseError {
+	pub line: usize,
+	pub col: usize,
+	pub kind: ErrorKind,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ErrorKind {
+	#[error("unterminated string")]
+	UnterminatedString,
+	#[error("unexpected {0}")]
+	Unexpected(String),
+	#[error("unknown directive \"{0}\"")]
+	UnknownDirective(String),
+	#[error("invalid address \"{0}\"")]
+	BadAddress(String),
+	#[error("invalid port \"{0}\"")]
+	BadPort(String),
+	#[error("invalid number \"{0}\"")]
+	BadNumber(String),
+	#[error("invalid network \"{0}\"")]
+	BadNetwork(String),
+	#[error("no server defined")]
+	NoServer,
+	#[error("server \"{0}\" has no listen directive")]
+	NoListen(String),
+	#[error("duplicate server name \"{0}\"")]
+	DuplicateServer(String),
+	#[error("{0} is listened on with different options")]
+	ListenConflict(Address),
+	#[error("\"{0}\" does not apply to this listener")]
+	ListenOption(String),
+	#[error("\"{0}\" needs the networks to trust")]
+	NoTrusted(String),
+	#[error("metrics on \"{0}\" need \"allow from\"")]
+	OpenMetrics(String),
+	#[error("invalid log style \"{0}\"")]
+	BadStyle(String),
+	#[error("prefork {0} is more than max connections {1}")]
+	PreforkOverMax(usize, usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Pos {
+	line: usize,
+	col: usize,
+}
+
+impl Pos {
+	fn error(self, kind: ErrorKind) -> ParseError {
+		ParseError { line: self.line, col: self.col, kind }
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+	Word(String),
+	Quoted(String),
+	OpenBrace,
+	CloseBrace,
+	Newline,
+	Eof,
+}
+
+impl fmt::Display for Token {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Token::Word(word) => write!(f, "\"{word}\""),
+			Token::Quoted(string) => write!(f, "string \"{string}\""),
+			Token::OpenBrace => write!(f, "'{{'"),
+			Token::CloseBrace => write!(f, "'}}'"),
+			Token::Newline => write!(f, "end of line"),
+			Token::Eof => write!(f, "end of file"),
+		}
+	}
+}
+
+struct Lexer<'a> {
+	chars: std::iter::Peekable<std::str::Chars<'a>>,
+	line: usize,
+	col: usize,
+}
+
+impl <'a> Lexer<'a> {
+	fn new(input: &'a str) -> Self {
+		Self { chars: input.chars().peekable(), line: 1, col: 1 }
+	}
+
+	fn bump(&mut self) -> Option<char> {
+		let c = self.chars.next()?;
+		if c == '\n' {
+			self.line += 1;
+			self.col = 1;
+		}
+		else {
+			self.col += 1;
+		}
+		Some(c)
+	}
+
+	fn next(&mut self) -> Result<(Pos, Token), ParseError> {
+		while let Some(c) = self.chars.peek() {
+			match c {
+				' ' | '\t' | '\r' => {
+					self.bump();
+				}
+				'#' => {
+					while self.chars.peek().is_some_and(|c| *c != '\n') {
+						self.bump();
+					}
+				}
+				_ => break,
+			}
+		}
+
+		let pos = Pos { line: self.line, col: self.col };
+		let token = match self.bump() {
+			None => Token::Eof,
+			Some('\n') => Token::Newline,
+			Some('{') => Token::OpenBrace,
+			Some('}') => Token::CloseBrace,
+			Some('"') => {
+				let mut string = String::new();
+				loop {
+					match self.bump() {
+						None | Some('\n') => {
+							return Err(pos.error(ErrorKind::UnterminatedString));
+						}
+						Some('"') => break,
+						Some('\\') => match self.bump() {
+							None | Some('\n') => {
+								return Err(pos.error(ErrorKind::UnterminatedString));
+							}
+							Some(c) => string.push(c),
+						},
+						Some(c) => string.push(c),
+					}
+				}
+				Token::Quoted(string)
+			}
+			Some(c) => {
+				let mut word = String::from(c);
+				while let Some(c) = self.chars.peek() {
+					if c.is_whitespace() || matches!(c, '{' | '}' | '"' | '#') {
+						break;
+					}
+					word.push(*c);
+					self.bump();
+				}
+				Token::Word(word)
+			}
+		};
+		Ok((pos, token))
+	}
+}
+
+struct Parser<'a> {
+	lexer: Lexer<'a>,
+	peeked: Option<(Pos, Token)>,
+}
+
+impl <'a> Parser<'a> {
+	fn new(input: &'a str) -> Self {
+		Self { lexer: Lexer::new(input), peeked: None }
+	}
+
+	fn next(&mut self) -> Result<(Pos, Token), ParseError> {
+		match self.peeked.take() {
+			Some(token) => Ok(token),
+			None => self.lexer.next(),
+		}
+	}
+
+	fn peek(&mut self) -> Result<&(Pos, Token), ParseError> {
+		if self.peeked.is_none() {
+			self.peeked = Some(self.lexer.next()?);
+		}
+		Ok(self.peeked.as_ref().unwrap())
+	}
+
+	fn skip_newlines(&mut self) -> Result<(), ParseError> {
+		while self.peek()?.1 == Token::Newline {
+			self.next()?;
+		}
+		Ok(())
+	}
+
+	/* Either a bare word or a quoted string */
+	fn string(&mut self) -> Result<(Pos, String), ParseError> {
+		match self.next()? {
+			(pos, Token::Word(word)) => Ok((pos, word)),
+			(pos, Token::Quoted(string)) => Ok((pos, string)),
+			(pos, token) => Err(pos.error(ErrorKind::Unexpected(token.to_string()))),
+		}
+	}
+
+	fn expect(&mut self, wanted: Token) -> Result<Pos, ParseError> {
+		match self.next()? {
+			(pos, token) if token == wanted => Ok(pos),
+			(pos, token) => Err(pos.error(ErrorKind::Unexpected(token.to_string()))),
+		}
+	}
+
+	fn number<T: std::str::FromStr>(&mut self) -> Result<T, ParseError> {
+		let (pos, number) = self.string()?;
+		number.parse().map_err(|_| pos.error(ErrorKind::BadNumber(number)))
+	}
+
+	fn count(&mut self) -> Result<usize, ParseError> {
+		let (pos, number) = self.string()?;
+		number.parse().ok().filter(|count| *count > 0)
+			.ok_or_else(|| pos.error(ErrorKind::BadNumber(number)))
+	}
+
+	fn keyword(&mut self, keyword: &str) -> Result<Pos, ParseError> {
+		self.expect(Token::Word(keyword.to_string()))
+	}
+
+	/* A directive ends at a newline, the end of the file or the
+	 * closing brace of the enclosing block
+	 */
+	fn end(&mut self) -> Result<(), ParseError> {
+		match self.peek()? {
+			(_, Token::CloseBrace | Token::Eof) => Ok(()),
+			(_, Token::Newline) => {
+				self.next()?;
+				Ok(())
+			}
+			(pos, token) => Err(pos.error(ErrorKind::Unexpected(token.to_string()))),
+		}
+	}
+
+	fn config(&mut self) -> Result<Config, ParseError> {
+		let mut config = Config {
+			user: DEFAULT_USER.to_string(),
+			group: None,
+			chroot: DEFAULT_CHROOT.to_string(),
+			empty: DEFAULT_EMPTY.to_string(),
+			types: DEFAULT_TYPES.to_string(),
+			drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+			max_connections: DEFAULT_MAX_CONNECTIONS,
+			prefork: cpus(),
+			error_log: log::Target::Syslog,
+			pidfile: DEFAULT_PIDFILE.to_string(),
+			control: Some(DEFAULT_CONTROL.to_string()),
+			seccomp: seccomp::Mode::Kill,
+			servers: Vec::new(),
+		};
+		/* Where prefork was set, it is cut down to max connections
+		 * only when it was not
+		 */
+		let mut prefork = None;
+		loop {
+			self.skip_newlines()?;
+			match self.next()? {
+				(pos, Token::Eof) => {
+					if config.servers.is_empty() {
+						return Err(pos.error(ErrorKind::NoServer));
+					}
+					break;
+				}
+				(_, Token::Word(word)) if word == "user" => {
+					config.user = self.string()?.1;
+				}
+				(_, Token::Word(word)) if word == "group" => {
+					config.group = Some(self.string()?.1);
+				}
+				/* chroot path | chroot empty path */
+				(_, Token::Word(word)) if word == "chroot" => {
+					if self.peek()?.1 == Token::Word("empty".to_string()) {
+						self.next()?;
+						config.empty = self.string()?.1;
+					}
+					else {
+						config.chroot = self.string()?.1;
+					}
+				}
+				(_, Token::Word(word)) if word == "types" => {
+					config.types = self.string()?.1;
+				}
+				(_, Token::Word(word)) if word == "pidfile" => {
+					config.pidfile = self.string()?.1;
+				}
+				/* control socket path */
+				(_, Token::Word(word)) if word == "control" => {
+					self.keyword("socket")?;
+					config.control = Some(self.string()?.1);
+				}
+				/* no control socket */
+				(_, Token::Word(word)) if word == "no" => {
+					self.keyword("control")?;
+					self.keyword("socket")?;
+					config.control = None;
+				}
+				/* drain timeout seconds */
+				(_, Token::Word(word)) if word == "drain" => {
+					self.keyword("timeout")?;
+					config.drain_timeout = Duration::from_secs(self.number()?);
+				}
+				/* max connections number */
+				(_, Token::Word(word)) if word == "max" => {
+					self.keyword("connections")?;
+					config.max_connections = self.count()?;
+				}
+				/* log error syslog | log error path */
+				(_, Token::Word(word)) if word == "log" => {
+					self.keyword("error")?;
+					config.error_log = match self.next()? {
+						(_, Token::Word(word)) if word == "syslog" => log::Target::Syslog,
+						(_, Token::Word(path) | Token::Quoted(path)) => log::Target::File(path),
+						(pos, token) => {
+							return Err(pos.error(ErrorKind::Unexpected(token.to_string())));
+						}
+					};
+				}
+				/* seccomp kill | seccomp log */
+				(_, Token::Word(word)) if word == "seccomp" => {
+					config.seccomp = match self.next()? {
+						(_, Token::Word(word)) if word == "kill" => seccomp::Mode::Kill,
+						(_, Token::Word(word)) if word == "log" => seccomp::Mode::Log,
+						(pos, token) => {
+							return Err(pos.error(ErrorKind::Unexpected(token.to_string())));
+						}
+					};
+				}
+				/* prefork number */
+				(pos, Token::Word(word)) if word == "prefork" => {
+					config.prefork = self.count()?;
+					prefork = Some(pos);
+				}
+				(pos, Token::Word(word)) if word == "server" => {
+					let server = self.server(pos)?;
+					for name in server.names() {
+						if config.servers.iter().any(|other| other.names().contains(&name)) {
+							return Err(pos.error(ErrorKind::DuplicateServer(name.to_string())));
+						}
+					}
+					let others = config.servers.iter().flat_map(|other| &other.listen);
+					if let Some(conflict) = Self::listen_conflict(&server.listen, others) {
+						return Err(pos.error(ErrorKind::ListenConflict(conflict)));
+					}
+					config.servers.push(server);
+				}
+				(pos, Token::Word(word)) => {
+					return Err(pos.error(ErrorKind::UnknownDirective(word)));
+				}
+				(pos, token) => {
+					return Err(pos.error(ErrorKind::Unexpected(token.to_string())));
+				}
+			}
+			self.end()?;
+		}
+		if config.prefork > config.max_connections {
+			if let Some(pos) = prefork {
+				let kind = ErrorKind::PreforkOverMax(config.prefork, config.max_connections);
+				return Err(pos.error(kind));
+			}
+			config.prefork = config.max_connections;
+		}
+		Ok(config)
+	}
+
+	fn server(&mut self, start: Pos) -> Result<Server, ParseError> {
+		let (_, name) = self.string()?;
+		let mut server = Server {
+			name,
+			aliases: Vec::new(),
+			listen: Vec::new(),
+			certificate: DEFAULT_CERTIFICATE.to_string(),
+			key: DEFAULT_KEY.to_string(),
+			root: DEFAULT_ROOT.to_string(),
+			index: Some(DEFAULT_INDEX.to_string()),
+			auto_index: true,
+			log: None,
+			log_style: logger::Style::Common,
+			locations: Vec::new(),
+		};
+		self.expect(Token::OpenBrace)?;
+		loop {
+			self.skip_newlines()?;
+			match self.next()? {
+				(_, Token::CloseBrace) => break,
+				(pos, Token::Word(word)) if word == "listen" => {
+					let listen = self.listen()?;
+					if let Some(conflict) = Self::listen_conflict(&listen, &server.listen) {
+						return Err(pos.error(ErrorKind::ListenConflict(conflict)));
+					}
+					for listen in listen {
+						if !server.listen.contains(&listen) {
+							server.listen.push(listen);
+						}
+					}
+				}
+				(_, Token::Word(word)) if word == "tls" => {
+					match self.string()? {
+						(_, word) if word == "certificate" => {
+							server.certificate = self.string()?.1;
+						}
+						(_, word) if word == "key" => {
+							server.key = self.string()?.1;
+						}
+						(pos, word) => {
+							return Err(pos.error(ErrorKind::UnknownDirective(word)));
+						}
+					}
+				}
+				(_, Token::Word(word)) if word == "alias" => {
+					server.aliases.push(self.string()?.1);
+				}
+				(_, Token::Word(word)) if word == "root" => {
+					server.root = self.string()?.1;
+				}
+				(_, Token::Word(word)) if word == "directory" => {
+					self.directory(&mut server)?;
+				}
+				(_, Token::Word(word)) if word == "location" => {
+					server.locations.push(self.location()?);
+				}
+				(_, Token::Word(word)) if word == "log" => {
+					self.log(&mut server)?;
+				}
+				(_, Token::Word(word)) if word == "no" => {
+					self.keyword("log")?;
+					server.log = None;
+				}
+				(pos, Token::Word(word)) => {
+					return Err(pos.error(ErrorKind::UnknownDirective(word)));
+				}
+				(pos, token) => {
+					return Err(pos.error(ErrorKind::Unexpected(token.to_string())));
+				}
+			}
+			self.end()?;
+		}
+		if server.listen.is_empty() {
+			return Err(start.error(ErrorKind::NoListen(server.name)));
+		}
+		/* Without any location nothing would be served */
+		if server.locations.is_empty() {
+			server.locations.push(Location {
+				path: "/".to_string(), block: false, allow: Vec::new(), metrics: false,
+			});
+		}
+		Ok(server)
+	}
+
+	/* Servers sharing an address share its listener, so they have to
+	 * agree on tls and the socket name
+	 */
+	fn listen_conflict<'b>(listen: &[Listen], others: impl IntoIterator<Item = &'b Listen>)
+	-> Option<Address> {
+		others.into_iter()
+			.find(|other| listen.iter().any(|l| l.addr == other.addr && l != *other))
+			.map(|other| other.addr.clone())
+	}
+
+	/* listen on address [tls] [port number] [name name] [proxy from network ...]
+	 *	[forwarded from network ...]
+	 * listen on path [owner user] [group group] [mode mode] [name name] [proxy]
+	 *	[forwarded]
+	 * "*" listens on all IPv4 and IPv6 addresses, a path starting
+	 * with "/" on a unix socket
+	 */
+	fn listen(&mut self) -> Result<Vec<Listen>, ParseError> {
+		const OPTIONS: [&str; 8] = [
+			"tls", "port", "name", "owner", "group", "mode", "proxy", "forwarded",
+		];
+		self.keyword("on")?;
+		let (pos, addr) = self.string()?;
+		let unix = addr.starts_with('/');
+		let ips = if unix {
+			Vec::new()
+		}
+		else if addr == "*" {
+			vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED), IpAddr::V6(Ipv6Addr::UNSPECIFIED)]
+		}
+		else {
+			vec![addr.parse().map_err(|_| pos.error(ErrorKind::BadAddress(addr.clone())))?]
+		};
+		let mut listen = Listen {
+			addr: Address::Unix(addr),
+			tls: false,
+			name: None,
+			owner: None,
+			group: None,
+			mode: None,
+			proxy: None,
+			forwarded: None,
+		};
+		let mut port = None;
+		loop {
+			let (pos, option) = match self.peek()? {
+				(pos, Token::Word(word)) if OPTIONS.contains(&word.as_str()) => (*pos, word.clone()),
+				_ => break,
+			};
+			self.next()?;
+			let applies = match option.as_str() {
+				"tls" | "port" => !unix,
+				"owner" | "group" | "mode" => unix,
+				_ => true,
+			};
+			if !applies {
+				return Err(pos.error(ErrorKind::ListenOption(option)));
+			}
+			match option.as_str() {
+				"tls" => listen.tls = true,
+				"port" => {
+					let (pos, number) = self.string()?;
+					let number = number.parse()
+						.map_err(|_| pos.error(ErrorKind::BadPort(number)))?;
+					port = Some(number);
+				}
+				"name" => listen.name = Some(self.string()?.1),
+				"owner" => listen.owner = Some(self.string()?.1),
+				"group" => listen.group = Some(self.string()?.1),
+				"proxy" => listen.proxy = Some(self.trusted(pos, &option, unix)?),
+				"forwarded" => listen.forwarded = Some(self.trusted(pos, &option, unix)?),
+				_ => {
+					let (pos, number) = self.string()?;
+					let mode = u32::from_str_radix(&number, 8).ok().filter(|mode| *mode <= 0o777)
+						.ok_or_else(|| pos.error(ErrorKind::BadNumber(number)))?;
+					listen.mode = Some(mode);
+				}
+			}
+		}
+		if unix {
+			return Ok(vec![listen]);
+		}
+		let port = port.unwrap_or(if listen.tls { 443 } else { 80 });
+		Ok(ips.into_iter()
+			.map(|ip| Listen { addr: Address::Inet(SocketAddr::new(ip, port)), ..listen.clone() })
+			.collect())
+	}
+
+	/* Peers on unix sockets have no address to tell them apart,
+	 * anyone else has to come from a network that is named
+	 */
+	fn trusted(&mut self, pos: Pos, option: &str, unix: bool)
+	-> Result<Vec<Network>, ParseError> {
+		let mut trusted = Vec::new();
+		while !unix && matches!(self.peek()?, (_, Token::Word(word)) if word == "from") {
+			self.next()?;
+			trusted.push(self.network()?);
+		}
+		if !unix && trusted.is_empty() {
+			return Err(pos.error(ErrorKind::NoTrusted(option.to_string())));
+		}
+		Ok(trusted)
+	}
+
+	fn network(&mut self) -> Result<Network, ParseError> {
+		let (pos, network) = self.string()?;
+		network.parse().map_err(|_| pos.error(ErrorKind::BadNetwork(network)))
+	}
+
+	/* directory index file
+	 * directory no index
+	 * directory [no] auto index
+	 */
+	fn directory(&mut self, server: &mut Server) -> Result<(), ParseError> {
+		let (pos, word) = self.string()?;
+		let (negated, pos, word) = if word == "no" {
+			let (pos, word) = self.string()?;
+			(true, pos, word)
+		}
+		else {
+			(false, pos, word)
+		};
+		match word.as_str() {
+			"index" if negated => server.index = None,
+			"index" => server.index = Some(self.string()?.1),
+			"auto" => {
+				self.keyword("index")?;
+				server.auto_index = !negated;
+			}
+			_ => return Err(pos.error(ErrorKind::UnknownDirective(word))),
+		}
+		Ok(())
+	}
+
+	/* log access path | log style common|combined|json */
+	fn log(&mut self, server: &mut Server) -> Result<(), ParseError> {
+		match self.string()? {
+			(_, word) if word == "access" => server.log = Some(self.string()?.1),
+			(_, word) if word == "style" => {
+				server.log_style = match self.string()? {
+					(_, style) if style == "common" => logger::Style::Common,
+					(_, style) if style == "combined" => logger::Style::Combined,
+					(_, style) if style == "json" => logger::Style::Json,
+					(pos, style) => return Err(pos.error(ErrorKind::BadStyle(style))),
+				};
+			}
+			(pos, word) => return Err(pos.error(ErrorKind::UnknownDirective(word))),
+		}
+		Ok(())
+	}
+
+	/* location path [{ block | metrics | allow from network ... }] */
+	fn location(&mut self) -> Result<Location, ParseError> {
+		let (start, path) = self.string()?;
+		let mut location = Location { path, block: false, allow: Vec::new(), metrics: false };
+		if self.peek()?.1 != Token::OpenBrace {
+			return Ok(location);
+		}
+		self.next()?;
+		loop {
+			self.skip_newlines()?;
+			match self.next()? {
+				(_, Token::CloseBrace) => break,
+				(_, Token::Word(word)) if word == "block" => {
+					location.block = true;
+				}
+				(_, Token::Word(word)) if word == "metrics" => {
+					location.metrics = true;
+				}
+				(_, Token::Word(word)) if word == "allow" => {
+					self.keyword("from")?;
+					location.allow.push(self.network()?);
+				}
+				(pos, Token::Word(word)) => {
+					return Err(pos.error(ErrorKind::UnknownDirective(word)));
+				}
+				(pos, token) => {
+					return Err(pos.error(ErrorKind::Unexpected(token.to_string())));
+				}
+			}
+			self.end()?;
+		}
+		if location.metrics && location.allow.is_empty() {
+			return Err(start.error(ErrorKind::OpenMetrics(location.path)));
+		}
+		Ok(location)
+	}
+}
+
+impl Config {
+	pub fn parse(input: &str) -> Result<Self, ParseError> {
+		Parser::new(input).config()
+	}
+
+	pub fn load(path: &str) -> Result<Self, Error> {
+		let input = std::fs::read_to_string(path)
+			.map_err(|source| Error::Io { path: path.to_string(), source })?;
+		Self::parse(&input)
+			.map_err(|source| Error::Parse { path: path.to_string(), source })
+	}
+
+	/* Servers sharing an address share a single listener */
+	pub fn listeners(&self) -> Vec<Listen> {
+		let mut listeners: Vec<Listen> = Vec::new();
+		for listen in self.servers.iter().flat_map(|server| &server.listen) {
+			if !listeners.contains(listen) {
+				listeners.push(listen.clone());
+			}
+		}
+		listeners
+	}
+
+	/* The share of max connections of each client, the first ones
+	 * take one more each until what is left over is used up
+	 */
+	pub fn client_connections(&self, client: usize) -> usize {
+		let left = self.max_connections % self.prefork;
+		self.max_connections / self.prefork + usize::from(client < left)
+	}
+
+	pub fn manager_config(&self) -> ManagerConfig<'_> {
+		let listeners = self.listeners();
+		let tls: Vec<_> = self.servers.iter()
+			.filter(|server| server.listen.iter().any(|listen| listen.tls))
+			.map(|server| TlsConfig {
+				names: server.names(),
+				cert: &server.certificate,
+				key: &server.key,
+			}).collect();
+		/* The certificate of the first server on a listener is the one
+		 * used without SNI, as its content is served without a Host
+		 */
+		let crypto = CryptoConfig {
+			servers: tls.iter()
+				.map(|tls| tls.names.iter().map(|name| name.to_string()).collect())
+				.collect(),
+			listeners: listeners.iter().map(|listen| {
+				let server = self.servers.iter().find(|server| server.listen.contains(listen))?;
+				let name = server.name.as_str();
+				tls.iter().position(|tls| tls.names[0] == name).filter(|_| listen.tls)
+			}).collect(),
+		};
+		let fs = self.servers.iter().map(|server| {
+			let locations = server.locations.iter()
+				.map(|location| fs::Location::new(&location.path, location.block, &location.allow,
+					location.metrics))
+				.collect();
+			fs::Server::new(&server.root, server.index.as_deref(), server.auto_index, locations)
+		}).collect();
+		let client = ClientConfig {
+			listeners: listeners.iter().map(|listen| ListenerConfig {
+				tls: listen.tls,
+				unix: matches!(listen.addr, Address::Unix(_)),
+				forwarded: listen.forwarded.clone(),
+				servers: self.servers.iter().enumerate()
+					.filter(|(_, server)| server.listen.contains(listen))
+					.map(|(idx, _)| idx)
+					.collect(),
+			}).collect(),
+			servers: self.servers.iter()
+				.map(|server| server.names().iter().map(|name| name.to_string()).collect())
+				.collect(),
+		};
+		/* Servers logging to the same file share it */
+		let mut logs: Vec<&str> = Vec::new();
+		let servers = self.servers.iter().map(|server| {
+			let log = server.log.as_deref()?;
+			let idx = logs.iter().position(|other| *other == log).unwrap_or_else(|| {
+				logs.push(log);
+				logs.len() - 1
+			});
+			Some((idx, server.log_style))
+		}).collect();
+		let logger = LoggerConfig { servers, files: logs.len() };
+		ManagerConfig {
+			tls, crypto, fs, client, logs, logger,
+		}
+	}
+}
+
+impl Server {
+	pub fn names(&self) -> Vec<&str> {
+		let mut names = vec![self.name.as_str()];
+		names.extend(self.aliases.iter().map(|alias| alias.as_str()));
+		names
+	}
+}
+
+struct Quoted<'a>(&'a str);
+
+impl fmt::Display for Quoted<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "\"")?;
+		for c in self.0.chars() {
+			if matches!(c, '"' | '\\') {
+				write!(f, "\\")?;
+			}
+			write!(f, "{c}")?;
+		}
+		write!(f, "\"")
+	}
+}
+
+/* Prints the configuration back in the same grammar with every
+ * default filled in, as used by -n
+ */
+impl fmt::Display for Config {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		writeln!(f, "user {}", Quoted(&self.user))?;
+		if let Some(group) = &self.group {
+			writeln!(f, "group {}", Quoted(group))?;
+		}
+		writeln!(f, "chroot {}", Quoted(&self.chroot))?;
+		writeln!(f, "chroot empty {}", Quoted(&self.empty))?;
+		writeln!(f, "types {}", Quoted(&self.types))?;
+		writeln!(f, "pidfile {}", Quoted(&self.pidfile))?;
+		match &self.control {
+			Some(path) => writeln!(f, "control socket {}", Quoted(path))?,
+			None => writeln!(f, "no control socket")?,
+		}
+		writeln!(f, "drain timeout {}", self.drain_timeout.as_secs())?;
+		writeln!(f, "max connections {}", self.max_connections)?;
+		writeln!(f, "prefork {}", self.prefork)?;
+		writeln!(f, "seccomp {}", self.seccomp)?;
+		match &self.error_log {
+			log::Target::Syslog => writeln!(f, "log error syslog")?,
+			log::Target::File(path) => writeln!(f, "log error {}", Quoted(path))?,
+		}
+		for server in &self.servers {
+			writeln!(f)?;
+			write!(f, "{server}")?;
+		}
+		Ok(())
+	}
+}
+
+impl fmt::Display for Server {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		writeln!(f, "server {} {{", Quoted(&self.name))?;
+		for alias in &self.aliases {
+			writeln!(f, "\talias {}", Quoted(alias))?;
+		}
+		for listen in &self.listen {
+			match &listen.addr {
+				Address::Inet(addr) => {
+					write!(f, "\tlisten on {}", addr.ip())?;
+					if listen.tls {
+						write!(f, " tls")?;
+					}
+					write!(f, " port {}", addr.port())?;
+				}
+				Address::Unix(path) => {
+					write!(f, "\tlisten on {}", Quoted(path))?;
+					if let Some(owner) = &listen.owner {
+						write!(f, " owner {}", Quoted(owner))?;
+					}
+					if let Some(group) = &listen.group {
+						write!(f, " group {}", Quoted(group))?;
+					}
+					if let Some(mode) = listen.mode {
+						write!(f, " mode {mode:03o}")?;
+					}
+				}
+			}
+			if let Some(name) = &listen.name {
+				write!(f, " name {}", Quoted(name))?;
+			}
+			for (option, trusted) in [("proxy", &listen.proxy), ("forwarded", &listen.forwarded)] {
+				if let Some(trusted) = trusted {
+					write!(f, " {option}")?;
+					for network in trusted {
+						write!(f, " from {network}")?;
+					}
+				}
+			}
+			writeln!(f)?;
+		}
+		if self.listen.iter().any(|listen| listen.tls) {
+			writeln!(f, "\ttls certificate {}", Quoted(&self.certificate))?;
+			writeln!(f, "\ttls key {}", Quoted(&self.key))?;
+		}
+		writeln!(f, "\troot {}", Quoted(&self.root))?;
+		match &self.index {
+			Some(index) => writeln!(f, "\tdirectory index {}", Quoted(index))?,
+			None => writeln!(f, "\tdirectory no index")?,
+		}
+		if self.auto_index {
+			writeln!(f, "\tdirectory auto index")?;
+		}
+		else {
+			writeln!(f, "\tdirectory no auto index")?;
+		}
+		match &self.log {
+			Some(log) => {
+				writeln!(f, "\tlog access {}", Quoted(log))?;
+				writeln!(f, "\tlog style {}", self.log_style)?;
+			}
+			None => writeln!(f, "\tno log")?,
+		}
+		for location in &self.locations {
+			write!(f, "\tlocation {}", Quoted(&location.path))?;
+			let flags: Vec<&str> = [(location.block, "block"), (location.metrics, "metrics")]
+				.into_iter()
+				.filter_map(|(set, flag)| set.then_some(flag))
+				.collect();
+			if !location.allow.is_empty() || flags.len() > 1 {
+				writeln!(f, " {{")?;
+				for flag in flags {
+					writeln!(f, "\t\t{flag}")?;
+				}
+				for network in &location.allow {
+					writeln!(f, "\t\tallow from {network}")?;
+				}
+				write!(f, "\t}}")?;
+			}
+			else if let [flag] = flags[..] {
+				write!(f, " {{ {flag} }}")?;
+			}
+			writeln!(f)?;
+		}
+		writeln!(f, "}}")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	fn listen(addr: &str, tls: bool) -> Listen {
+		Listen {
+			addr: Address::Inet(addr.parse().unwrap()), tls,
+			name: None, owner: None, group: None, mode: None, proxy: None, forwarded: None,
+		}
+	}
+	fn location(path: &str, block: bool) -> Location {
+		Location { path: path.to_string(), block, allow: Vec::new(), metrics: false }
+	}
+	#[test]
+	fn parse() {
+		let config = include_str!("../tests/httpd.conf");
+		let config = Config::parse(config).unwrap();
+		let wanted = Config {
+			user: "www".to_string(),
+			group: Some("www".to_string()),
+			chroot: "/var/www".to_string(),
+			empty: "/var/empty/httpd".to_string(),
+			types: "tests/mime.types".to_string(),
+			drain_timeout: Duration::from_secs(10),
+			max_connections: 512,
+			prefork: 2,
+			error_log: log::Target::File("/var/log/httpd.err".to_string()),
+			pidfile: "/var/run/httpd-example.pid".to_string(),
+			control: Some("/var/run/httpd-example.sock".to_string()),
+			seccomp: seccomp::Mode::Log,
+			servers: vec![
+				Server {
+					name: "example.com".to_string(),
+					aliases: vec!["www.example.com".to_string()],
+					listen: vec![
+						listen("127.0.0.1:443", true),
+					],
+					certificate: "/etc/ssl/example.com.crt".to_string(),
+					key: DEFAULT_KEY.to_string(),
+					root: "/htdocs/example.com".to_string(),
+					index: Some(DEFAULT_INDEX.to_string()),
+					auto_index: true,
+					log: Some("/var/www/logs/example.com.log".to_string()),
+					log_style: logger::Style::Combined,
+					locations: vec![
+						location("/", false),
+						location("/private/", true),
+						Location {
+							path: "/admin/".to_string(), block: false,
+							allow: vec!["192.0.2.0/24".parse().unwrap()], metrics: false,
+						},
+						Location {
+							path: "/status/".to_string(), block: false,
+							allow: vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()],
+							metrics: true,
+						},
+					],
+				},
+				Server {
+					name: "example.org".to_string(),
+					aliases: Vec::new(),
+					listen: vec![
+						listen("127.0.0.1:443", true),
+					],
+					certificate: "/etc/ssl/example.org.crt".to_string(),
+					key: "/etc/ssl/private/example.org.key".to_string(),
+					root: DEFAULT_ROOT.to_string(),
+					index: None,
+					auto_index: false,
+					log: None,
+					log_style: logger::Style::Common,
+					locations: vec![
+						location("/", false),
+					],
+				},
+			],
+		};
+		assert_eq!(config, wanted);
+	}
+
+	#[test]
+	fn normalize() {
+		let config = include_str!("../tests/httpd.conf");
+		let config = Config::parse(config).unwrap();
+		let normalized = config.to_string();
+		assert_eq!(Config::parse(&normalized).unwrap(), config);
+		assert!(normalized.contains("\tlisten on 127.0.0.1 tls port 443\n"));
+
+		let config = Config::parse("no control socket\nserver a {\n\tlisten on * port 80\n}\n")
+			.unwrap();
+		assert_eq!(config.control, None);
+		assert_eq!(Config::parse(&config.to_string()).unwrap(), config);
+	}
+
+	#[test]
+	fn listeners() {
+		let config = Config::parse("server a {\n\tlisten on * port 80\n\tlisten on ::1 tls\n}\n\
+			server b {\n\tlisten on 0.0.0.0 port 80\n}\n").unwrap();
+		let wanted = vec![
+			listen("0.0.0.0:80", false),
+			listen("[::]:80", false),
+			listen("[::1]:443", true),
+		];
+		assert_eq!(config.listeners(), wanted);
+		let client = config.manager_config().client;
+		assert_eq!(client.listeners[0].servers, vec![0, 1]);
+		assert_eq!(client.listeners[1].servers, vec![0]);
+
+		/* Without SNI each listener has the certificate of the server
+		 * whose content it serves by default
+		 */
+		let config = Config::parse("server a {\n\tlisten on 127.0.0.1 tls\n}\n\
+			server b {\n\tlisten on 127.0.0.1 tls\n\tlisten on ::1 tls port 8443\n}\n\
+			server c {\n\tlisten on ::1\n}\n").unwrap();
+		let crypto = config.manager_config().crypto;
+		assert_eq!(crypto.servers, vec![vec!["a".to_string()], vec!["b".to_string()]]);
+		assert_eq!(crypto.listeners, vec![Some(0), Some(1), None]);
+
+		let error = Config::parse("server a {\n\tlisten on * port 80\n}\n\
+			server b {\n\tlisten on :: tls port 80\n}\n");
+		let wanted = ParseError {
+			line: 4, col: 1, kind: ErrorKind::ListenConflict(listen("[::]:80", false).addr),
+		};
+		assert_eq!(error, Err(wanted));
+
+		let config = Config::parse("server a {\n\tlisten on ::1 name \"web\"\n}\n").unwrap();
+		assert_eq!(config.listeners()[0].name.as_deref(), Some("web"));
+		let error = Config::parse("server a {\n\tlisten on ::1 name web\n}\n\
+			server b {\n\tlisten on ::1\n}\n");
+		let wanted = ParseError {
+			line: 4, col: 1, kind: ErrorKind::ListenConflict(listen("[::1]:80", false).addr),
+		};
+		assert_eq!(error, Err(wanted));
+
+		let config = Config::parse("server a {\n\tlisten on \"/run/httpd.sock\" owner www mode 660\n}\n")
+			.unwrap();
+		let unix = &config.listeners()[0];
+		assert_eq!(unix.addr, Address::Unix("/run/httpd.sock".to_string()));
+		assert_eq!((unix.owner.as_deref(), unix.mode), (Some("www"), Some(0o660)));
+		assert!(config.manager_config().client.listeners[0].unix);
+		let config = Config::parse("server a {\n\tlisten on * proxy from 10.0.0.0/8 from ::1\n\
+			\tlisten on /run/httpd.sock proxy forwarded\n\
+			\tlisten on ::1 port 8080 forwarded from ::1\n}\n").unwrap();
+		let trusted = vec!["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()];
+		assert_eq!(config.listeners()[1].proxy, Some(trusted));
+		assert_eq!(config.listeners()[2].proxy, Some(Vec::new()));
+		assert_eq!(config.listeners()[2].forwarded, Some(Vec::new()));
+		assert_eq!(config.listeners()[3].forwarded, Some(vec!["::1".parse().unwrap()]));
+		assert_eq!(Config::parse(&config.to_string()).unwrap(), config);
+		let error = Config::parse("server a {\n\tlisten on * proxy tls\n}\n");
+		let wanted = ParseError {
+			line: 2, col: 14, kind: ErrorKind::NoTrusted("proxy".to_string()),
+		};
+		assert_eq!(error, Err(wanted));
+		let error = Config::parse("server a {\n\tlisten on /run/httpd.sock tls\n}\n");
+		let wanted = ParseError {
+			line: 2, col: 28, kind: ErrorKind::ListenOption("tls".to_string()),
+		};
+		assert_eq!(error, Err(wanted));
+	}
+
+	#[test]
+	fn max_connections() {
+		let server = "server a {\n\tlisten on * port 80\n}\n";
+		let config = Config::parse(&format!("max connections 10\nprefork 4\n{server}")).unwrap();
+		let shares: Vec<usize> = (0..4).map(|client| config.client_connections(client)).collect();
+		assert_eq!(shares, [3, 3, 2, 2]);
+		let config = Config::parse(&format!("max connections 4\nprefork 4\n{server}")).unwrap();
+		assert_eq!(config.client_connections(3), 1);
+
+		let error = Config::parse(&format!("max connections 2\nprefork 4\n{server}"));
+		let wanted = ParseError {
+			line: 2, col: 1, kind: ErrorKind::PreforkOverMax(4, 2),
+		};
+		assert_eq!(error, Err(wanted));
+		let config = Config::parse(&format!("max connections 1\n{server}")).unwrap();
+		assert_eq!(config.prefork, 1);
+	}
+
+	#[test]
+	fn errors() {
+		let error = Config::parse("server \"a\" {\n\tlisten on 127.0.0.1 port http\n}\n");
+		let wanted = ParseError {
+			line: 2, col: 27, kind: ErrorKind::BadPort("http".to_string()),
+		};
+		assert_eq!(error, Err(wanted));
+
+		let error = Config::parse("types \"/etc/mime.types\n");
+		let wanted = ParseError {
+			line: 1, col: 7, kind: ErrorKind::UnterminatedString,
+		};
+		assert_eq!(error, Err(wanted));
+
+		let error = Config::parse("server \"a\" {\n\tlisten on * port 80\n\tchroot \"/\"\n}\n");
+		let wanted = ParseError {
+			line: 3, col: 2, kind: ErrorKind::UnknownDirective("chroot".to_string()),
+		};
+		assert_eq!(error, Err(wanted));
+
+		let error = Config::parse("server a {\n\tlisten on * port 80\n}\n\
+			server b {\n\talias a\n\tlisten on * port 80\n}\n");
+		let wanted = ParseError {
+			line: 4, col: 1, kind: ErrorKind::DuplicateServer("a".to_string()),
+		};
+		assert_eq!(error, Err(wanted));
+
+		let error = Config::parse("server a {\n\tlisten on * port 80\n\
+			\tlocation /status/ { metrics }\n}\n");
+		let wanted = ParseError {
+			line: 3, col: 11, kind: ErrorKind::OpenMetrics("/status/".to_string()),
+		};
+		assert_eq!(error, Err(wanted));
+
+		let error = Config::parse("max connections 0\n");
+		let wanted = ParseError {
+			line: 1, col: 17, kind: ErrorKind::BadNumber("0".to_string()),
+		};
+		assert_eq!(error, Err(wanted));
+	}
+}
@@ -0,0 +1,272 @@
+/* The control socket, on which httpd -p ctl asks the parent for its
+ * state or tells it what to do. Requests and responses are CBOR, a
+ * message each
+ */
+use crate::client::Remote;
+use nix::sys::socket::{AddressFamily, SockFlag, SockType, UnixAddr};
+use serde_derive::{Serialize, Deserialize};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio_seqpacket::{UnixSeqpacket, UnixSeqpacketListener};
+
+/* How long either side waits for the other */
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Request {
+	Status,
+	Reload,
+	Reopen,
+	Connections,
+	Stop,
+}
+
+/* Status is answered with Status, Connections with a Connection
+ * message each and Done, everything else with Done. Failed can
+ * answer any of them
+ */
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+	Status(Status),
+	Connection(Connection),
+	Done,
+	Failed(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+	pub pid: u32,
+	pub uptime: Duration,
+	pub children: Vec<Child>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Child {
+	pub role: String,
+	pub pid: Option<u32>,
+	/* Draining connections after a reload */
+	pub retired: bool,
+	/* For clients that answered in time */
+	pub connections: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Connection {
+	/* Of the client process serving it */
+	pub pid: Option<u32>,
+	pub listener: String,
+	pub remote: Remote,
+	pub since: SystemTime,
+	pub requests: u64,
+}
+
+/* Only the owner may connect, the mode is set before listening so
+ * that nobody else gets in between. A socket left behind is replaced
+ * unless another server still answers on it. Bound by the helper,
+ * which also removes it
+ */
+pub async fn bind(path: &str) -> std::io::Result<OwnedFd> {
+	use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+	match std::fs::symlink_metadata(path) {
+		Ok(metadata) if metadata.file_type().is_socket() => {
+			if UnixSeqpacket::connect(path).await.is_ok() {
+				return Err(std::io::Error::new(std::io::ErrorKind::AddrInUse,
+					"in use by another server"));
+			}
+			std::fs::remove_file(path)?;
+		}
+		Ok(_) => return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists,
+			"exists and is not a socket")),
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+		Err(err) => return Err(err),
+	}
+	let socket = nix::sys::socket::socket(AddressFamily::Unix, SockType::SeqPacket,
+		SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK, None)?;
+	nix::sys::socket::bind(socket.as_raw_fd(), &UnixAddr::new(path)?)?;
+	if let Err(err) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)) {
+		let _ = std::fs::remove_file(path);
+		return Err(err);
+	}
+	nix::sys::socket::listen(&socket, 16)?;
+	Ok(socket)
+}
+
+pub struct Server {
+	listener: UnixSeqpacketListener,
+	/* The user the server was started as */
+	owner: u32,
+}
+
+impl Server {
+	pub fn new(socket: OwnedFd, owner: u32) -> std::io::Result<Self> {
+		let listener = UnixSeqpacketListener::try_from(socket)?;
+		Ok(Self { listener, owner })
+	}
+
+	/* Run as a task of its own, every connection is read in another
+	 * and its request passed on, nobody waits on a slow client
+	 */
+	pub async fn serve(mut self, requests: mpsc::Sender<(Request, UnixSeqpacket)>) {
+		loop {
+			let socket = match self.accept().await {
+				Ok(socket) => socket,
+				Err(err) => {
+					crate::log::warning!("control socket: {err}");
+					continue;
+				}
+			};
+			let requests = requests.clone();
+			tokio::spawn(async move {
+				match recv(&socket).await {
+					Ok(request) => {
+						let _ = requests.send((request, socket)).await;
+					}
+					Err(err) => crate::log::warning!("control socket: {err}"),
+				}
+			});
+		}
+	}
+
+	/* Turns away anyone but root and the user the server was started
+	 * as, in case the mode was changed behind its back
+	 */
+	pub async fn accept(&mut self) -> std::io::Result<UnixSeqpacket> {
+		loop {
+			let socket = self.listener.accept().await?;
+			let uid = socket.peer_cred()?.uid();
+			if uid == 0 || uid == self.owner {
+				return Ok(socket);
+			}
+			crate::log::warning!("control socket: refused user {uid}");
+		}
+	}
+}
+
+pub async fn recv(socket: &UnixSeqpacket) -> std::io::Result<Request> {
+	let mut buf = [0u8; 128];
+	let len = tokio::time::timeout(TIMEOUT, socket.recv(&mut buf)).await??;
+	if len == 0 {
+		return Err(std::io::ErrorKind::UnexpectedEof.into());
+	}
+	serde_cbor::from_slice(&buf[..len]).map_err(|_| std::io::ErrorKind::InvalidData.into())
+}
+
+pub async fn send(socket: &UnixSeqpacket, response: &Response) -> std::io::Result<()> {
+	let buf = serde_cbor::to_vec(response).expect("serde");
+	socket.send(&buf).await?;
+	Ok(())
+}
+
+/* Sends the responses in turn, giving up on the first that fails */
+pub async fn reply(socket: &UnixSeqpacket, responses: &[Response]) {
+	for response in responses {
+		if let Err(err) = send(socket, response).await {
+			crate::log::warning!("control socket: {err}");
+			break;
+		}
+	}
+}
+
+/* httpd -p ctl command */
+pub async fn main(path: &str, command: &[String]) -> ! {
+	let request = match command.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+		["status"] => Request::Status,
+		["reload"] => Request::Reload,
+		["reopen"] => Request::Reopen,
+		["connections"] => Request::Connections,
+		["stop"] => Request::Stop,
+		_ => {
+			eprintln!("usage: httpd [-f file] -p ctl status | reload | reopen | connections | stop");
+			std::process::exit(1);
+		}
+	};
+	if let Err(err) = run(path, request).await {
+		eprintln!("httpd: {path}: {err}");
+		std::process::exit(1);
+	}
+	std::process::exit(0);
+}
+
+async fn run(path: &str, request: Request) -> std::io::Result<()> {
+	let socket = UnixSeqpacket::connect(path).await?;
+	let buf = serde_cbor::to_vec(&request).expect("serde");
+	socket.send(&buf).await?;
+	/* A reload waits for the new children to start */
+	let mut buf = vec![0u8; 65536];
+	loop {
+		let len = tokio::time::timeout(TIMEOUT * 6, socket.recv(&mut buf)).await??;
+		if len == 0 {
+			return Err(std::io::ErrorKind::UnexpectedEof.into());
+		}
+		let response: Response = serde_cbor::from_slice(&buf[..len])
+			.map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidData))?;
+		match response {
+			Response::Status(status) => {
+				print!("{status}");
+				return Ok(());
+			}
+			Response::Connection(connection) => println!("{connection}"),
+			Response::Done => return Ok(()),
+			Response::Failed(err) => return Err(std::io::Error::other(err)),
+		}
+	}
+}
+
+/* The age of something, in the largest units that fit */
+struct Age(Duration);
+
+impl std::fmt::Display for Age {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let secs = self.0.as_secs();
+		match (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60) {
+			(0, 0, 0, s) => write!(f, "{s}s"),
+			(0, 0, m, s) => write!(f, "{m}m{s}s"),
+			(0, h, m, _) => write!(f, "{h}h{m}m"),
+			(d, h, _, _) => write!(f, "{d}d{h}h"),
+		}
+	}
+}
+
+impl std::fmt::Display for Status {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		writeln!(f, "parent pid {} up {}", self.pid, Age(self.uptime))?;
+		for child in &self.children {
+			write!(f, "{}", child.role)?;
+			match child.pid {
+				Some(pid) => write!(f, " pid {pid}")?,
+				None => write!(f, " exited")?,
+			}
+			if child.retired {
+				write!(f, " retired")?;
+			}
+			if let Some(connections) = child.connections {
+				write!(f, " connections {connections}")?;
+			}
+			writeln!(f)?;
+		}
+		Ok(())
+	}
+}
+
+impl std::fmt::Display for Connection {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let age = SystemTime::now().duration_since(self.since).unwrap_or_default();
+		let pid = self.pid.map_or("-".to_string(), |pid| pid.to_string());
+		write!(f, "{} on {} pid {pid} age {} requests {}", self.remote, self.listener, Age(age),
+			self.requests)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[test]
+	fn age() {
+		let age = |secs| Age(Duration::from_secs(secs)).to_string();
+		assert_eq!(age(5), "5s");
+		assert_eq!(age(65), "1m5s");
+		assert_eq!(age(3 * 3600 + 125), "3h2m");
+		assert_eq!(age(2 * 86400 + 3600), "2d1h");
+	}
+}
@@ -1,9 +1,45 @@
-use crate::{proc, tls, client::HttpVersion};
+use crate::{log, proc, proxy, tls, client::HttpVersion};
+use crate::metrics::Metrics;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::net::{TcpStream, UnixStream};
-use tokio::io::AsyncWriteExt;
-use pledge::pledge;
+use tokio::io::{AsyncWriteExt, BufReader};
+use proc::pledge;
+use serde::{Serialize, Deserialize};
+use tokio::task::JoinSet;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/* The names of each server, sent with the shared metrics. A
+ * certificate and key pair is sent for every one of them afterwards
+ */
+#[derive(Serialize, Deserialize)]
+pub struct CryptoConfig {
+	pub servers: Vec<Vec<String>>,
+	/* For each listener, the server whose certificate is used when
+	 * the client asks for no name or one that is not known
+	 */
+	pub listeners: Vec<Option<usize>>,
+}
+
+/* A connection to decrypt, sent along with it and the client
+ * process's end of the decrypted stream
+ */
+#[derive(Serialize, Deserialize)]
+pub struct Accept {
+	pub listener: usize,
+	/* The connection starts with a PROXY protocol header */
+	pub proxy: bool,
+}
+
+/* Sent back once the certificates are loaded, or with why one could
+ * not be, after which the process exits
+ */
+pub type Ready = Result<(), String>;
+
+/* How long the parent waits for it */
+pub const READY_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub async fn main() -> ! {
 	pledge("stdio recvfd", None).expect("pledge");
@@ -12,64 +48,139 @@ pub async fn main() -> ! {
 		proc::Peer::get_parent()
 	};
 
-	let (certfile, keyfile) = {
-		let (_, (cfd, pfd)) = parent.recv_with_fds(&mut []).await.expect("no file descriptors");
-		let c = std::fs::File::from(cfd);
-		let p = std::fs::File::from(pfd);
-		(c, p)
+	let (crypto_config, metrics) = {
+		let mut buf = vec![0u8; 65536];
+		let (len, metrics) = parent.recv_with_fd(&mut buf).await.expect("no file descriptor");
+		let config: CryptoConfig = serde_cbor::from_slice(&buf[..len]).expect("serde");
+		(config, Metrics::map(&metrics).expect("metrics"))
 	};
 
-	let cert = tls::certs_from_file(certfile).unwrap();
-	let key = tls::keys_from_file(keyfile).unwrap();
+	let certificates = certificates(&parent, &crypto_config).await;
+	let ready: Ready = certificates.as_ref().map(|_| ()).map_err(String::clone);
+	let buf = serde_cbor::to_vec(&ready).expect("serde");
+	let sent = parent.socket().send(&buf).await;
+	let certificates = match (certificates, sent) {
+		(Ok(certificates), Ok(_)) => certificates,
+		(Err(err), _) => {
+			log::crit!("{err}");
+			std::process::exit(1);
+		}
+		(_, Err(err)) => {
+			log::crit!("parent: {err}");
+			std::process::exit(1);
+		}
+	};
 
-	let mut config = rustls::server::ServerConfig::builder()
-		.with_no_client_auth()
-		.with_single_cert(cert, key)
-		.unwrap();
-	config.alpn_protocols = vec![b"http/1.1".to_vec(), b"http/1.0".to_vec()];
-	let config = Arc::new(config);
+	/* A configuration for each listener, for its default certificate */
+	let configs: Vec<_> = certificates.resolvers(&crypto_config.listeners).into_iter()
+		.map(|resolver| {
+			let mut config = rustls::server::ServerConfig::builder()
+				.with_no_client_auth()
+				.with_cert_resolver(Arc::new(resolver));
+			config.alpn_protocols = vec![b"http/1.1".to_vec(), b"http/1.0".to_vec()];
+			Arc::new(config)
+		})
+		.collect();
 
-	let mut sigint = signal(SignalKind::terminate()).expect("signal");
+	let mut sigterm = signal(SignalKind::terminate()).expect("signal");
+	let mut tasks = JoinSet::new();
+	let mut buf = [0u8; 128];
 
+	/* On SIGTERM, or when the parent retires this process, the
+	 * connections it already has are finished
+	 */
 	loop {
 		tokio::select! {
-			_ = sigint.recv() => { break }
+			_ = sigterm.recv() => { break }
 
-			res = parent.recv_with_fds(&mut []) => {
-				let (client, server) = {
-					let (_, (cfd, sfd)) = res.unwrap();
+			Some(_) = tasks.join_next() => {}
+
+			res = parent.recv_with_fds(&mut buf) => {
+				let (accept, client, server) = {
+					let (len, (cfd, sfd)) = match res {
+						Ok(res) => res,
+						Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+						Err(err) => {
+							log::crit!("parent sent bad stream: {err}");
+							std::process::exit(1);
+						}
+					};
 					let client = std::net::TcpStream::from(cfd);
 					let client = TcpStream::from_std(client).unwrap();
 					let server = std::os::unix::net::UnixStream
 						::from(sfd);
 					let server = UnixStream::from_std(server).unwrap();
-					(client, server)
+					let accept: Accept = match serde_cbor::from_slice(&buf[..len]) {
+						Ok(accept) => accept,
+						Err(err) => {
+							log::crit!("parent sent bad message: {err}");
+							std::process::exit(1);
+						}
+					};
+					(accept, client, server)
+				};
+				let Some(config) = configs.get(accept.listener) else {
+					log::crit!("parent sent bad listener {}", accept.listener);
+					std::process::exit(1);
 				};
 				let acceptor = tokio_rustls::TlsAcceptor::from(config.clone());
 				let stream = CryptoStream {
-					client, server, acceptor
+					client: BufReader::new(client), server, acceptor, proxy: accept.proxy, metrics,
 				};
-				tokio::spawn(async move {
+				tasks.spawn(async move {
 					if let Err(err) = stream.run().await {
-						eprintln!("{err}");
+						log::info!("{err}");
 					}
 				});
 			}
 		}
 	}
 
+	while tasks.join_next().await.is_some() {}
 	std::process::exit(0);
 }
 
+async fn certificates(parent: &proc::Peer, config: &CryptoConfig)
+-> Result<tls::Certificates, String> {
+	let mut certificates = tls::Certificates::default();
+	for names in &config.servers {
+		let (_, (cfd, pfd)) = parent.recv_with_fds(&mut []).await
+			.map_err(|err| format!("parent: {err}"))?;
+		let key = tls::certified_key(&std::fs::File::from(cfd), &std::fs::File::from(pfd))
+			.map_err(|err| format!("server \"{}\": {err}", names[0]))?;
+		certificates.add(names, key);
+	}
+	Ok(certificates)
+}
+
 struct CryptoStream {
-	client: TcpStream,
+	/* Buffered, so that reading the PROXY protocol header does not
+	 * take bytes of the handshake
+	 */
+	client: BufReader<TcpStream>,
 	server: UnixStream,
 	acceptor: tokio_rustls::TlsAcceptor,
+	proxy: bool,
+	metrics: Metrics,
 }
 
 impl CryptoStream {
+	/* The client process is told the HTTP version and the address a
+	 * PROXY protocol header gave, if any, ahead of the decrypted bytes
+	 */
 	async fn run(mut self) -> std::io::Result<()> {
-		let mut client = self.acceptor.accept(self.client).await?;
+		let remote: Option<SocketAddr> = match self.proxy {
+			true => tokio::time::timeout(proxy::TIMEOUT, proxy::read(&mut self.client)).await??,
+			false => None,
+		};
+		let mut client = match self.acceptor.accept(self.client).await {
+			Ok(client) => client,
+			Err(err) => {
+				self.metrics.handshake_failures.fetch_add(1, Ordering::Relaxed);
+				return Err(err);
+			}
+		};
+		self.metrics.handshakes.fetch_add(1, Ordering::Relaxed);
 		let version = match client.get_ref().1.alpn_protocol() {
 			Some(b"http/1.0") => HttpVersion::One,
 			Some(b"http/1.1") => HttpVersion::OneOne,
@@ -77,6 +188,9 @@ impl CryptoStream {
 		};
 		let byte: u8 = version.into();
 		self.server.write_u8(byte).await?;
+		let remote = serde_cbor::to_vec(&remote).expect("serde");
+		self.server.write_u8(remote.len().try_into().expect("address")).await?;
+		self.server.write_all(&remote).await?;
 		tokio::io::copy_bidirectional(&mut client, &mut self.server).await?;
 		client.flush().await?;
 		Ok(())
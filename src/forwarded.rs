@@ -0,0 +1,140 @@
+/* Forwarded (RFC 7239) and X-Forwarded-For/-Proto, with which proxies
+ * in front of the server name the clients they speak for
+ */
+use crate::client::Remote;
+use crate::http::Request;
+use crate::network::Network;
+use std::net::{IpAddr, SocketAddr};
+
+/* Who a request is from, and whether it reached the first proxy over
+ * TLS
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Origin {
+	pub remote: Remote,
+	pub https: bool,
+}
+
+/* One proxy's account of where it got the request from */
+struct Hop {
+	addr: Option<SocketAddr>,
+	https: Option<bool>,
+}
+
+/* The hops are taken from the right, as long as the one naming them
+ * is trusted. Where a hop has no address the last proxy has to do,
+ * it is as much as is known. Without trusted networks only a peer on
+ * a unix socket is believed, and no proxy beyond it
+ */
+pub fn origin(request: &Request, peer: Origin, trusted: &[Network]) -> Origin {
+	let forwarded = request.header_values("Forwarded");
+	let hops: Vec<Hop> = if !forwarded.is_empty() {
+		forwarded.iter().map(|element| element_hop(element)).collect()
+	}
+	else {
+		let proto = request.header_values("X-Forwarded-Proto").last()
+			.and_then(|proto| scheme(proto));
+		request.header_values("X-Forwarded-For").iter()
+			.map(|addr| Hop { addr: node(addr), https: proto })
+			.collect()
+	};
+
+	let mut origin = peer;
+	for hop in hops.iter().rev() {
+		let Some(addr) = hop.addr else {
+			break;
+		};
+		if !origin.remote.trusted(trusted) {
+			break;
+		}
+		origin = Origin {
+			remote: Remote::Inet(addr),
+			https: hop.https.unwrap_or(origin.https),
+		};
+	}
+	origin
+}
+
+/* for=node;proto=scheme;by=node, in any order */
+fn element_hop(element: &str) -> Hop {
+	let mut hop = Hop { addr: None, https: None };
+	for pair in element.split(';') {
+		let Some((key, value)) = pair.split_once('=') else {
+			continue;
+		};
+		let value = value.trim().trim_matches('"');
+		match key.trim().to_ascii_lowercase().as_str() {
+			"for" => hop.addr = node(value),
+			"proto" => hop.https = scheme(value),
+			_ => {}
+		}
+	}
+	hop
+}
+
+/* An address with or without a port, IPv6 ones possibly in brackets.
+ * "unknown" and obfuscated identifiers give None
+ */
+fn node(node: &str) -> Option<SocketAddr> {
+	let node = node.trim().trim_matches('"');
+	if let Ok(addr) = node.parse() {
+		return Some(addr);
+	}
+	let ip = node.strip_prefix('[').and_then(|node| node.strip_suffix(']')).unwrap_or(node);
+	let ip: IpAddr = ip.parse().ok()?;
+	Some(SocketAddr::new(ip, 0))
+}
+
+fn scheme(proto: &str) -> Option<bool> {
+	match proto.trim().to_ascii_lowercase().as_str() {
+		"https" => Some(true),
+		"http" => Some(false),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::io::BufReader;
+	async fn parse(headers: &str) -> Request {
+		let buf = format!("GET / HTTP/1.1\r\n{headers}\r\n");
+		Request::read(&mut BufReader::new(buf.as_bytes())).await.unwrap()
+	}
+	fn peer(addr: &str) -> Origin {
+		Origin { remote: Remote::Inet(addr.parse().unwrap()), https: false }
+	}
+	fn inet(addr: &str) -> Remote {
+		Remote::Inet(addr.parse().unwrap())
+	}
+	#[tokio::test]
+	async fn origin() {
+		let trusted: Vec<Network> = vec!["10.0.0.0/8".parse().unwrap()];
+		let request = parse("X-Forwarded-For: 192.0.2.1, 198.51.100.2, 10.0.0.2\r\n\
+			X-Forwarded-Proto: https\r\n").await;
+		let origin = super::origin(&request, peer("10.0.0.1:4711"), &trusted);
+		assert_eq!(origin, Origin { remote: inet("198.51.100.2:0"), https: true });
+		/* Not from a trusted proxy, so not believed */
+		assert_eq!(super::origin(&request, peer("203.0.113.1:4711"), &trusted),
+			peer("203.0.113.1:4711"));
+		/* Nobody is believed without trusted networks */
+		assert_eq!(super::origin(&request, peer("10.0.0.1:4711"), &[]), peer("10.0.0.1:4711"));
+
+		let request = parse("Forwarded: for=\"[2001:db8::1]:4711\";proto=https, \
+			For=192.0.2.60;proto=http\r\n").await;
+		let proxies: Vec<Network> = vec!["10.0.0.0/8".parse().unwrap(), "192.0.2.0/24".parse().unwrap()];
+		let origin = super::origin(&request, peer("10.0.0.1:4711"), &proxies);
+		assert_eq!(origin, Origin { remote: inet("[2001:db8::1]:4711"), https: true });
+		let origin = super::origin(&request, peer("10.0.0.1:4711"), &trusted);
+		assert_eq!(origin, Origin { remote: inet("192.0.2.60:0"), https: false });
+
+		/* The proxy does not know, so it is the best there is */
+		let request = parse("Forwarded: for=unknown, for=10.0.0.2\r\n").await;
+		let origin = super::origin(&request, peer("10.0.0.1:4711"), &trusted);
+		assert_eq!(origin.remote, inet("10.0.0.2:0"));
+
+		let unix = Origin { remote: Remote::Unix, https: false };
+		let request = parse("X-Forwarded-For: 192.0.2.1\r\n").await;
+		assert_eq!(super::origin(&request, unix, &trusted).remote, inet("192.0.2.1:0"));
+	}
+}
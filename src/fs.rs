@@ -1,94 +1,143 @@
-use crate::proc;
+use crate::{log, proc};
+use crate::network::Network;
 use proc::{pledge, unveil};
 use serde_derive::{Serialize, Deserialize};
 use tokio_seqpacket::UnixSeqpacket;
 use tokio_seqpacket::ancillary::{OwnedAncillaryMessage};
 use std::os::fd::{OwnedFd};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::fs;
 use std::sync::Arc;
+use tokio::task::JoinSet;
 
-pub async fn main() -> ! {
+pub async fn main(chroot: &str) -> ! {
 	pledge("stdio sendfd recvfd rpath unveil", None).expect("pledge");
 	let parent = unsafe {
 		proc::Peer::get_parent()
 	};
 
-	let mut buf: [u8; 4096] = [0; 4096];
-	let (len, fd) = parent.recv_with_fd(&mut buf).await.expect("read");
-	let peer = {
-		let seq = UnixSeqpacket::try_from(fd).unwrap();
-		proc::Peer::from_stream(seq)
+	let mut servers: Vec<Server> = {
+		let mut buf = vec![0u8; 65536];
+		let len = parent.socket().recv(&mut buf).await.expect("read");
+		serde_cbor::from_slice(&buf[..len]).expect("serde")
 	};
-	let server: Server = serde_cbor::from_slice(&buf[..len]).expect("idk");
-	let server = Arc::new(server);
-
-	for location in &server.locations {
-		if location.blocked {
-			unveil(&location.path, "").expect("unveil");
+	if proc::unprivileged() {
+		for server in &mut servers {
+			server.confine(chroot);
 		}
-		else {
-			unveil(&location.path, "r").expect("unveil");
+	}
+	let servers = Arc::new(servers);
+
+	for server in servers.iter() {
+		for location in &server.locations {
+			let path = format!("{}{}", server.root, location.path);
+			if location.blocked {
+				unveil(&path, "").expect("unveil");
+			}
+			else {
+				unveil(&path, "r").expect("unveil");
+			}
 		}
 	}
 	pledge("stdio sendfd recvfd rpath", None).expect("pledge");
 
-	let mut buf: [u8; 4096] = [0; 4096];
-
-	let mut sigterm = signal(SignalKind::terminate()).expect("sigaction");
+	/* SIGTERM is ignored, the clients may still be answering requests,
+	 * this process exits once the parent and every client have closed
+	 * their channels
+	 */
+	let _sigterm = signal(SignalKind::terminate()).expect("sigaction");
 
+	/* The parent sends a channel for each client process, including
+	 * the ones started to replace a client that exited
+	 */
+	let mut clients = JoinSet::new();
+	let mut parent_open = true;
 	loop {
 		tokio::select! {
-			resp = peer.recv_with_fd(&mut buf) => {
-				let server = server.clone();
-				tokio::spawn(async move {
-				let (len, stream) = match resp {
-					Ok((len, fd)) => {
-						let stream = UnixSeqpacket::try_from(fd).unwrap();
-						(len, stream)
-					}
-					Err(err) => {
-						eprintln!("error: {err}");
-						return;
-					}
-				};
-				let buf = &buf[..len];
-				let mut peer = proc::Peer::from_stream(stream);
-				let message: RecvMessageClient = serde_cbor::from_slice(buf)
-					.expect("serde_cbor");
-				server.handle_request(&mut peer, &message).await.expect("handle_request");
-				});
-			}
-			_ = sigterm.recv() => { 
-				break;
-			}
+			resp = parent.recv_with_fd(&mut []), if parent_open => match resp {
+				Ok((_, fd)) => {
+					let seq = UnixSeqpacket::try_from(fd).unwrap();
+					let peer = proc::Peer::from_stream(seq);
+					clients.spawn(serve(peer, servers.clone()));
+				}
+				Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => parent_open = false,
+				Err(err) => {
+					log::crit!("parent sent bad channel: {err}");
+					std::process::exit(1);
+				}
+			},
+			Some(_) = clients.join_next() => {},
+			else => break,
 		}
-	};
+	}
 
 	std::process::exit(0);
 }
 
+async fn serve(peer: proc::Peer, servers: Arc<Vec<Server>>) {
+	let mut buf: [u8; 4096] = [0; 4096];
+	loop {
+		let resp = peer.recv_with_fd(&mut buf).await;
+		/* The client process has gone away */
+		if resp.as_ref().is_err_and(|err| err.kind() == std::io::ErrorKind::UnexpectedEof) {
+			break;
+		}
+		let servers = servers.clone();
+		tokio::spawn(async move {
+			let (len, stream) = match resp {
+				Ok((len, fd)) => {
+					let stream = UnixSeqpacket::try_from(fd).unwrap();
+					(len, stream)
+				}
+				Err(err) => {
+					log::error!("{err}");
+					return;
+				}
+			};
+			let buf = &buf[..len];
+			let mut peer = proc::Peer::from_stream(stream);
+			let message: RecvMessageClient = serde_cbor::from_slice(buf)
+				.expect("serde_cbor");
+			let server = match message {
+				RecvMessageClient::Open { server, .. } => servers.get(server),
+			};
+			match server {
+				Some(server) => {
+					server.handle_request(&mut peer, &message).await
+						.expect("handle_request");
+				}
+				None => {
+					OpenResponse::FileError(FileError::NotFound).send(&peer).await
+						.expect("send");
+				}
+			}
+		});
+	}
+}
+
 impl Server {
 	async fn handle_request(&self, peer: &mut proc::Peer, request: &RecvMessageClient<'_>) 
 	-> std::io::Result<()> {
 		match request {
-			RecvMessageClient::Open(open) => self.handle_open(peer, open).await,
+			RecvMessageClient::Open { path, remote, .. } => self.handle_open(peer, path, *remote).await,
 		}
 	}
 
-	async fn handle_open(&self, peer: &mut proc::Peer, path: &str)
+	async fn handle_open(&self, peer: &mut proc::Peer, path: &str, remote: Option<IpAddr>)
 	-> std::io::Result<()> 
 	{
-		let mut response = self.open(path).await;
+		let mut response = self.open(path, remote).await;
 		let resp = match response {
 			Err(err) => OpenResponse::FileError(err),
-			Ok(File::File(file)) => {
+			Ok(Opened::File(name, file)) => {
 				let file = std::fs::File::from(file);
 				let file = tokio::fs::File::from_std(file);
-				let info = FileInfo { name: path.to_string() };
+				let info = FileInfo { name };
 				OpenResponse::File(info, file)
 			}
-			Ok(File::Dir(ref mut dir)) => {
+			Ok(Opened::Dir(ref mut dir)) => {
 				let mut vec = Vec::new();
 				while let Some(entry) = dir.next_entry().await? {
 					let name = entry.file_name();
@@ -99,6 +148,7 @@ impl Server {
 				}
 				OpenResponse::Dir(vec)
 			}
+			Ok(Opened::Metrics) => OpenResponse::Metrics,
 		};
 		resp.send(peer).await?;
 		Ok(())
@@ -109,22 +159,54 @@ impl Server {
 pub struct Location {
 	path: String,
 	blocked: bool,
+	allow: Vec<Network>,
+	metrics: bool,
 }
 
 impl Location {
-	pub fn new(path: &str, blocked: bool) -> Self {
-		Self { path: path.to_string(), blocked }
+	pub fn new(path: &str, blocked: bool, allow: &[Network], metrics: bool) -> Self {
+		Self { path: path.to_string(), blocked, allow: allow.to_vec(), metrics }
+	}
+
+	/* Clients without an address are let in only where anyone is */
+	fn allows(&self, remote: Option<IpAddr>) -> bool {
+		self.allow.is_empty()
+			|| remote.is_some_and(|ip| self.allow.iter().any(|network| network.contains(ip)))
 	}
 }
 
+/* root is relative to the chroot of the filesystem process,
+ * location paths are relative to root
+ */
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Server {
+	root: String,
+	index: Option<String>,
+	auto_index: bool,
 	locations: Vec<Location>,
+	/* The directory standing in for the chroot when there is none */
+	#[serde(skip)]
+	jail: Option<PathBuf>,
 }
 
 impl Server {
-	pub fn new(locations: Vec<Location>) -> Self {
-		Self { locations }
+	pub fn new(root: &str, index: Option<&str>, auto_index: bool, locations: Vec<Location>) 
+	-> Self {
+		Self {
+			root: root.trim_end_matches('/').to_string(),
+			index: index.map(|index| index.to_string()),
+			auto_index,
+			locations,
+			jail: None,
+		}
+	}
+
+	/* Without a chroot, root is taken relative to the directory that
+	 * would have been the chroot and every request is kept beneath it
+	 */
+	pub fn confine(&mut self, chroot: &str) {
+		self.root = format!("{}{}", chroot.trim_end_matches('/'), self.root);
+		self.jail = Some(PathBuf::from(chroot));
 	}
 }
 
@@ -138,6 +220,8 @@ pub enum OpenResponse {
 	FileError(FileError),
 	File(FileInfo, tokio::fs::File),
 	Dir(Vec<FileInfo>),
+	/* The client answers with the metrics it shares with the others */
+	Metrics,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -145,6 +229,7 @@ enum SerdeOpenResponse {
 	FileError(FileError),
 	File(FileInfo),
 	Dir(Vec<FileInfo>),
+	Metrics,
 }
 
 impl From<OpenResponse> for SerdeOpenResponse {
@@ -153,6 +238,7 @@ impl From<OpenResponse> for SerdeOpenResponse {
 			OpenResponse::FileError(err) => Self::FileError(err),
 			OpenResponse::Dir(dir) => Self::Dir(dir),
 			OpenResponse::File(info, _) => Self::File(info),
+			OpenResponse::Metrics => Self::Metrics,
 		}
 	}
 }
@@ -183,6 +269,7 @@ impl OpenResponse {
 		match message {
 			SerdeOpenResponse::Dir(dir) => Ok(Self::Dir(dir)),
 			SerdeOpenResponse::FileError(err) => Ok(Self::FileError(err)),
+			SerdeOpenResponse::Metrics => Ok(Self::Metrics),
 			SerdeOpenResponse::File(file) => {
 				let mut messages = ancillary.into_messages();
 				let fd = match messages.next() {
@@ -219,9 +306,10 @@ impl From<std::io::Error> for FileError {
 }
 
 #[derive(Debug)]
-enum File {
-	File(OwnedFd),
+enum Opened {
+	File(String, OwnedFd),
 	Dir(tokio::fs::ReadDir),
+	Metrics,
 }
 
 /* The path has to start with the location
@@ -242,31 +330,79 @@ impl Server {
 		}
 		best
 	}
-	async fn open(&self, path: &str) -> Result<File, FileError> {
-		let path = std::path::Path::new(path).canonicalize()?;
-		let path = path.to_str().expect("Not valid UTF-8?");
-		match self.matching(path) {
+	/* Resolves a request path to a file beneath root, the result has
+	 * to stay beneath root once symbolic links are followed.
+	 * Directories are checked against the locations with a trailing
+	 * slash, so that "/private" is blocked by "/private/"
+	 */
+	async fn resolve(&self, path: &str, remote: Option<IpAddr>) -> Result<(PathBuf, bool), FileError> {
+		let root = Path::new(if self.root.is_empty() { "/" } else { &self.root });
+		let root = root.canonicalize()?;
+		if let Some(jail) = &self.jail {
+			if !root.starts_with(jail.canonicalize()?) {
+				return Err(FileError::NotAllowed);
+			}
+		}
+		let full = root.join(path.trim_start_matches('/')).canonicalize()?;
+		let relative = full.strip_prefix(&root)
+			.map_err(|_| FileError::NotAllowed)?;
+		let relative = relative.to_str().expect("Not valid UTF-8?");
+		let is_dir = fs::metadata(&full).await?.is_dir();
+		let mut relative = format!("/{relative}");
+		if is_dir && !relative.ends_with('/') {
+			relative.push('/');
+		}
+		match self.matching(&relative) {
 			None => {
 				return Err(FileError::NotAllowed);
 			}
 			Some(matched) => {
-				if matched.blocked {
+				if matched.blocked || !matched.allows(remote) {
 					return Err(FileError::NotAllowed);
 				}
 			}
 		}
-		let file = fs::File::open(path).await?;
+		Ok((full, is_dir))
+	}
+
+	async fn open(&self, path: &str, remote: Option<IpAddr>) -> Result<Opened, FileError> {
+		/* There is nothing on disk to resolve */
+		if let Some(matched) = self.matching(path).filter(|matched| matched.metrics) {
+			if matched.blocked || !matched.allows(remote) {
+				return Err(FileError::NotAllowed);
+			}
+			return Ok(Opened::Metrics);
+		}
+		let (mut full, is_dir) = self.resolve(path, remote).await?;
+		let mut name = path.to_string();
+		if is_dir {
+			let index = match &self.index {
+				Some(index) => self.resolve(&format!("{path}/{index}"), remote).await.ok(),
+				None => None,
+			};
+			match index {
+				Some((index, false)) => {
+					name = index.file_name()
+						.and_then(|name| name.to_str())
+						.unwrap_or_default()
+						.to_string();
+					full = index;
+				}
+				_ if self.auto_index => {
+					/* Under pledge you can't send directory file descriptors,
+					 * so this has to do
+					 */
+					let dir = tokio::fs::read_dir(&full).await?;
+					return Ok(Opened::Dir(dir));
+				}
+				_ => return Err(FileError::NotAllowed),
+			}
+		}
+		let file = fs::File::open(&full).await?;
 		let metadata = file.metadata().await?;
 		let fd = OwnedFd::from(file.into_std().await);
-		if metadata.is_dir() {
-			/* Under pledge you can't send directory file descriptors,
-			 * so this has to do
-			 */
-			let dir = tokio::fs::read_dir(path).await?;
-			Ok(File::Dir(dir))
-		}
-		else if metadata.is_file() {
-			Ok(File::File(fd))
+		if metadata.is_file() {
+			Ok(Opened::File(name, fd))
 		}
 		else {
 			/* This is a character device or something?
@@ -279,7 +415,12 @@ impl Server {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RecvMessageClient<'a> {
-	Open(&'a str),
+	Open {
+		server: usize,
+		path: &'a str,
+		/* The client's address, for locations that allow only some */
+		remote: Option<IpAddr>,
+	},
 }
 
 #[cfg(test)]
@@ -288,14 +429,55 @@ mod tests {
 	#[test]
 	fn matching() {
 		let server = Server {
+			root: String::new(),
+			index: None,
+			auto_index: false,
 			locations: vec![
-				Location { path: "/".to_string(), blocked: false },
-				Location { path: "/home/".to_string(), blocked: true },
+				Location::new("/", false, &[], false),
+				Location::new("/home/", true, &[], false),
+				Location::new("/admin/", false, &["192.0.2.0/24".parse().unwrap()], false),
 			],
+			jail: None,
 		};
 		let matched = server.matching("/tmp/normalstuff").unwrap();
 		assert!(!matched.blocked);
 		let matched = server.matching("/home/user/secretstuff").unwrap();
 		assert!(matched.blocked);
+		let matched = server.matching("/admin/index.html").unwrap();
+		assert!(matched.allows(Some("192.0.2.7".parse().unwrap())));
+		assert!(!matched.allows(Some("198.51.100.7".parse().unwrap())));
+		assert!(!matched.allows(None));
+	}
+
+	#[tokio::test]
+	async fn confine() {
+		let dir = std::env::temp_dir().join(format!("httpd-confine-{}", std::process::id()));
+		let htdocs = dir.join("chroot/htdocs");
+		std::fs::create_dir_all(&htdocs).unwrap();
+		std::fs::write(htdocs.join("index.html"), "index").unwrap();
+		std::fs::write(dir.join("outside.txt"), "outside").unwrap();
+		std::os::unix::fs::symlink(dir.join("outside.txt"), htdocs.join("link")).unwrap();
+		std::fs::create_dir(htdocs.join("sub")).unwrap();
+		let chroot = dir.join("chroot");
+		let chroot = chroot.to_str().unwrap();
+
+		let mut server = Server::new("/htdocs", None, false, vec![Location::new("/", false, &[], false)]);
+		server.confine(chroot);
+		assert!(server.resolve("/index.html", None).await.is_ok());
+		assert!(matches!(server.resolve("/link", None).await, Err(FileError::NotAllowed)));
+		assert!(matches!(server.resolve("/../../outside.txt", None).await, Err(FileError::NotAllowed)));
+		assert!(matches!(server.open("/sub/", None).await, Err(FileError::NotAllowed)));
+
+		let status = Location::new("/status/", false, &["127.0.0.1".parse().unwrap()], true);
+		let server = Server::new("/htdocs", None, false, vec![Location::new("/", false, &[], false), status]);
+		let local = Some("127.0.0.1".parse().unwrap());
+		assert!(matches!(server.open("/status/metrics", local).await, Ok(Opened::Metrics)));
+		assert!(matches!(server.open("/status/", None).await, Err(FileError::NotAllowed)));
+
+		let mut server = Server::new("/..", None, false, vec![Location::new("/", false, &[], false)]);
+		server.confine(chroot);
+		assert!(matches!(server.resolve("/outside.txt", None).await, Err(FileError::NotAllowed)));
+
+		std::fs::remove_dir_all(&dir).unwrap();
 	}
 }
@@ -0,0 +1,571 @@
+/* The privileged helper. The parent serves without privileges in the
+ * empty chroot and asks this process for everything that takes them:
+ * reading the configuration, binding listeners, opening the files the
+ * configuration names and starting the children, whose exits it
+ * passes back. It does so only for what a configuration it read
+ * itself asks for. Once the parent is gone it removes the pidfile and
+ * the sockets it created.
+ *
+ * Requests are CBOR, each one comes with a socket of its own for the
+ * answer, which may carry a descriptor. Exits are sent on the channel
+ * itself, in between
+ */
+use crate::config::{Address, Config, Listen};
+use crate::{control, log, proc, seccomp};
+use nix::sys::signal::Signal;
+use nix::sys::socket::{AddressFamily, SockFlag, SockType, UnixAddr, setsockopt, sockopt};
+use nix::unistd::ForkResult;
+use serde_derive::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpSocket;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+use tokio_seqpacket::UnixSeqpacket;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+	/* The configuration file, read again */
+	Config,
+	Bind(Address),
+	/* The control socket of the configuration it started with */
+	Control,
+	Open(String),
+	Spawn(Spawn),
+	/* Children are told apart by a number of their own, process
+	 * ids are reused
+	 */
+	Signal(u64, i32),
+	/* A unix socket it bound that is no longer listened on */
+	Unlink(Address),
+	Exit,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Spawn {
+	pub role: String,
+	pub user: String,
+	pub group: Option<String>,
+	pub chroot: String,
+	pub seccomp: seccomp::Mode,
+}
+
+/* Opened and Spawned come with a descriptor, the channel to the
+ * child for the latter
+ */
+#[derive(Debug, Serialize, Deserialize)]
+enum Reply {
+	Done,
+	Config(String),
+	Opened,
+	Spawned(u64, u32),
+	Failed(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Exited {
+	id: u64,
+	status: i32,
+}
+
+pub enum Fork {
+	/* The channel to the helper */
+	Parent(OwnedFd),
+	/* The channel to the parent, see main() */
+	Helper(OwnedFd),
+}
+
+/* Has to be called before any runtime exists, as it forks. The
+ * parent keeps its process id, which is the one in the pidfile
+ */
+pub fn fork() -> std::io::Result<Fork> {
+	let (parent, helper) = nix::sys::socket::socketpair(AddressFamily::Unix, SockType::SeqPacket,
+		None, SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK)?;
+	match unsafe { nix::unistd::fork() }? {
+		ForkResult::Parent { .. } => Ok(Fork::Parent(parent)),
+		ForkResult::Child => Ok(Fork::Helper(helper)),
+	}
+}
+
+enum Child {
+	Running,
+	Exited(ExitStatus),
+	/* Dropped while running, until its exit comes in */
+	Forgotten,
+}
+
+/* The parent's end */
+pub struct Helper {
+	channel: proc::Peer,
+	/* An exit may come in ahead of the answer to the spawn request */
+	children: Mutex<HashMap<u64, Child>>,
+	/* Bumped on every exit, and once the helper is gone */
+	exits: watch::Sender<u64>,
+	gone: std::sync::atomic::AtomicBool,
+}
+
+impl Helper {
+	pub fn new(channel: OwnedFd) -> std::io::Result<Arc<Self>> {
+		let channel = proc::Peer::from_stream(UnixSeqpacket::try_from(channel)?);
+		let helper = Arc::new(Self {
+			channel,
+			children: Mutex::default(),
+			exits: watch::Sender::new(0),
+			gone: std::sync::atomic::AtomicBool::new(false),
+		});
+		tokio::spawn(helper.clone().events());
+		Ok(helper)
+	}
+
+	async fn events(self: Arc<Self>) {
+		let mut buf = [0u8; 128];
+		loop {
+			let exited: Exited = match self.channel.socket().recv(&mut buf).await {
+				Ok(0) | Err(_) => break,
+				Ok(len) => match serde_cbor::from_slice(&buf[..len]) {
+					Ok(exited) => exited,
+					Err(_) => continue,
+				},
+			};
+			let status = ExitStatus::from_raw(exited.status);
+			let mut children = self.children.lock().unwrap();
+			if let Some(Child::Forgotten) = children.insert(exited.id, Child::Exited(status)) {
+				children.remove(&exited.id);
+			}
+			drop(children);
+			self.exits.send_modify(|exits| *exits += 1);
+		}
+		self.gone.store(true, std::sync::atomic::Ordering::Relaxed);
+		self.exits.send_modify(|exits| *exits += 1);
+	}
+
+	/* Changes whenever a child exits or the helper goes away */
+	pub fn exits(&self) -> watch::Receiver<u64> {
+		self.exits.subscribe()
+	}
+
+	pub fn gone(&self) -> bool {
+		self.gone.load(std::sync::atomic::Ordering::Relaxed)
+	}
+
+	async fn request(&self, request: &Request) -> std::io::Result<(Reply, Option<OwnedFd>)> {
+		let (mine, theirs) = UnixSeqpacket::pair()?;
+		let buf = serde_cbor::to_vec(request).expect("serde");
+		self.channel.send_with_fd(theirs, &buf).await?;
+		let mut buf = vec![0u8; 1 << 20];
+		let (len, fd) = proc::Peer::from_stream(mine).recv_with_optional_fd(&mut buf).await?;
+		if len == 0 {
+			return Err(std::io::Error::other("helper exited"));
+		}
+		let reply = serde_cbor::from_slice(&buf[..len])
+			.map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidData))?;
+		match reply {
+			Reply::Failed(err) => Err(std::io::Error::other(err)),
+			reply => Ok((reply, fd)),
+		}
+	}
+
+	async fn request_fd(&self, request: &Request) -> std::io::Result<OwnedFd> {
+		match self.request(request).await? {
+			(_, Some(fd)) => Ok(fd),
+			(_, None) => Err(std::io::ErrorKind::InvalidData.into()),
+		}
+	}
+
+	/* The text of the configuration file, which the helper found
+	 * valid
+	 */
+	pub async fn config(&self) -> std::io::Result<String> {
+		match self.request(&Request::Config).await? {
+			(Reply::Config(text), _) => Ok(text),
+			_ => Err(std::io::ErrorKind::InvalidData.into()),
+		}
+	}
+
+	pub async fn bind(&self, addr: &Address) -> std::io::Result<OwnedFd> {
+		self.request_fd(&Request::Bind(addr.clone())).await
+	}
+
+	pub async fn control(&self) -> std::io::Result<OwnedFd> {
+		self.request_fd(&Request::Control).await
+	}
+
+	/* Log files are opened for appending, anything else for reading */
+	pub async fn open(&self, path: &str) -> std::io::Result<std::fs::File> {
+		self.request_fd(&Request::Open(path.to_string())).await.map(std::fs::File::from)
+	}
+
+	pub async fn spawn(self: &Arc<Self>, spawn: Spawn) -> std::io::Result<proc::Process> {
+		match self.request(&Request::Spawn(spawn)).await? {
+			(Reply::Spawned(id, pid), Some(fd)) => {
+				self.children.lock().unwrap().entry(id).or_insert(Child::Running);
+				let peer = proc::Peer::from_stream(UnixSeqpacket::try_from(fd)?);
+				Ok(proc::Process::new(peer, id, pid, self.clone()))
+			}
+			_ => Err(std::io::ErrorKind::InvalidData.into()),
+		}
+	}
+
+	pub async fn signal(&self, id: u64, signal: Signal) -> std::io::Result<()> {
+		self.request(&Request::Signal(id, signal as i32)).await.map(|_| ())
+	}
+
+	pub async fn unlink(&self, addr: &Address) -> std::io::Result<()> {
+		self.request(&Request::Unlink(addr.clone())).await.map(|_| ())
+	}
+
+	/* Answered once the helper removed what it created */
+	pub async fn exit(&self) -> std::io::Result<()> {
+		self.request(&Request::Exit).await.map(|_| ())
+	}
+
+	pub fn status(&self, id: u64) -> Option<ExitStatus> {
+		match self.children.lock().unwrap().get(&id) {
+			Some(Child::Exited(status)) => Some(*status),
+			_ => None,
+		}
+	}
+
+	/* Returns once the child exited, or the helper is gone */
+	pub async fn wait(&self, id: u64) {
+		let mut exits = self.exits();
+		while self.status(id).is_none() && !self.gone() {
+			if exits.changed().await.is_err() {
+				return;
+			}
+		}
+	}
+
+	pub fn forget(&self, id: u64) {
+		let mut children = self.children.lock().unwrap();
+		if let Some(Child::Running) = children.remove(&id) {
+			children.insert(id, Child::Forgotten);
+		}
+	}
+}
+
+struct State {
+	prog: String,
+	path: String,
+	/* Every configuration read so far, a retired generation may still
+	 * need what an older one named
+	 */
+	configs: Vec<Config>,
+	sockets: Vec<String>,
+	control: Option<String>,
+	next: u64,
+	children: HashMap<u64, mpsc::UnboundedSender<Signal>>,
+}
+
+/* Does not return, the pidfile and the sockets are removed as the
+ * parent exits or asks it to
+ */
+pub fn main(channel: OwnedFd, prog: String, path: String, config: Config,
+pidfile: Option<proc::Pidfile>) -> ! {
+	let mut state = State {
+		prog, path,
+		configs: vec![config],
+		sockets: Vec::new(),
+		control: None,
+		next: 0,
+		children: HashMap::new(),
+	};
+	crate::runtime().block_on(async {
+		/* Signals for the server go to the parent, the service
+		 * manager may send them to both
+		 */
+		let _signals = [SignalKind::hangup(), SignalKind::interrupt(), SignalKind::terminate(),
+			SignalKind::user_defined1()].map(|kind| signal(kind).expect("signal"));
+		/* It starts the children and binds and opens for them, what
+		 * the parent used to promise
+		 */
+		proc::pledge("stdio rpath cpath inet unix fattr chown sendfd recvfd proc exec", None)
+			.expect("pledge");
+		let exit = serve(channel, &mut state).await;
+		for path in state.sockets.iter().chain(&state.control) {
+			let _ = std::fs::remove_file(path);
+		}
+		drop(pidfile);
+		if let Some(exit) = exit {
+			reply(&exit, Reply::Done, None).await;
+		}
+	});
+	std::process::exit(0);
+}
+
+/* Returns the socket to answer the exit request on, if there was one */
+async fn serve(channel: OwnedFd, state: &mut State) -> Option<proc::Peer> {
+	let channel = match UnixSeqpacket::try_from(channel) {
+		Ok(channel) => Arc::new(proc::Peer::from_stream(channel)),
+		Err(err) => {
+			log::crit!("helper: {err}");
+			return None;
+		}
+	};
+	let mut buf = vec![0u8; 4096];
+	loop {
+		let (len, fd) = match channel.recv_with_fd(&mut buf).await {
+			Ok(message) => message,
+			Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return None,
+			/* Requests come with a socket for the answer */
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+			Err(err) => {
+				log::error!("helper: {err}");
+				return None;
+			}
+		};
+		let Ok(socket) = UnixSeqpacket::try_from(fd) else {
+			continue;
+		};
+		let socket = proc::Peer::from_stream(socket);
+		let Ok(request) = serde_cbor::from_slice::<Request>(&buf[..len]) else {
+			continue;
+		};
+		log::debug!("helper: {request:?}");
+		if let Request::Exit = request {
+			return Some(socket);
+		}
+		let (answer, fd) = match state.handle(request, &channel).await {
+			Ok(answer) => answer,
+			Err(err) => (Reply::Failed(err.to_string()), None),
+		};
+		reply(&socket, answer, fd).await;
+	}
+}
+
+async fn reply(socket: &proc::Peer, reply: Reply, fd: Option<OwnedFd>) {
+	let buf = serde_cbor::to_vec(&reply).expect("serde");
+	let _ = match fd {
+		Some(fd) => socket.send_with_fd(fd, &buf).await,
+		None => socket.socket().send(&buf).await.map(|_| ()),
+	};
+}
+
+fn refused(what: &str) -> std::io::Error {
+	std::io::Error::new(std::io::ErrorKind::PermissionDenied,
+		format!("{what} is not in the configuration"))
+}
+
+impl State {
+	async fn handle(&mut self, request: Request, channel: &Arc<proc::Peer>)
+	-> std::io::Result<(Reply, Option<OwnedFd>)> {
+		match request {
+			Request::Config => {
+				let text = std::fs::read_to_string(&self.path)
+					.map_err(|err| std::io::Error::new(err.kind(), format!("{}: {err}", self.path)))?;
+				let config = Config::parse(&text)
+					.map_err(|err| std::io::Error::other(format!("{}:{err}", self.path)))?;
+				if !self.configs.contains(&config) {
+					self.configs.push(config);
+				}
+				Ok((Reply::Config(text), None))
+			}
+			Request::Bind(addr) => {
+				let listen = self.configs.iter()
+					.flat_map(|config| config.listeners())
+					.find(|listen| listen.addr == addr)
+					.ok_or_else(|| refused(&addr.to_string()))?;
+				let fd = bind(&listen)?;
+				if let Address::Unix(path) = addr {
+					if !self.sockets.contains(&path) {
+						self.sockets.push(path);
+					}
+				}
+				Ok((Reply::Opened, Some(fd)))
+			}
+			Request::Control => {
+				let path = self.configs[0].control.clone()
+					.ok_or_else(|| refused("control socket"))?;
+				let fd = control::bind(&path).await
+					.map_err(|err| std::io::Error::new(err.kind(), format!("{path}: {err}")))?;
+				self.control = Some(path);
+				Ok((Reply::Opened, Some(fd)))
+			}
+			Request::Open(path) => {
+				/* The error log is the one it started with */
+				let error_log = log::file() == Some(path.as_str());
+				let (read, append) = self.configs.iter().fold((false, error_log), |(read, append), config| {
+					let manager_config = config.manager_config();
+					let read = read || config.types == path || manager_config.tls.iter()
+						.any(|tls| tls.cert == path || tls.key == path);
+					(read, append || manager_config.logs.contains(&path.as_str()))
+				});
+				let file = match (read, append) {
+					(_, true) => std::fs::OpenOptions::new().append(true).create(true).open(&path),
+					(true, false) => std::fs::File::open(&path),
+					(false, false) => return Err(refused(&path)),
+				};
+				let file = file.map_err(|err| std::io::Error::new(err.kind(), format!("{path}: {err}")))?;
+				/* Rotated, the helper's own lines go to the new one too */
+				if error_log {
+					if let Ok(own) = file.try_clone() {
+						log::reopen(own);
+					}
+				}
+				Ok((Reply::Opened, Some(file.into())))
+			}
+			Request::Spawn(spawn) => {
+				let config = self.configs.iter()
+					.find(|config| {
+						config.user == spawn.user && config.group == spawn.group
+							&& config.seccomp == spawn.seccomp
+							&& (config.chroot == spawn.chroot || config.empty == spawn.chroot)
+					})
+					.ok_or_else(|| refused(&format!("user \"{}\" in {}", spawn.user, spawn.chroot)))?;
+				if !["client", "crypto", "filesystem", "logger"].contains(&spawn.role.as_str()) {
+					return Err(refused(&spawn.role));
+				}
+				check(config).map_err(std::io::Error::other)?;
+				let (fd, child) = proc::ProcessBuilder::new(&self.prog, &spawn.role, &spawn.user,
+					&spawn.chroot)
+					.group(spawn.group.as_deref())
+					.seccomp(spawn.seccomp)
+					.spawn()?;
+				let pid = child.id().unwrap_or_default();
+				let id = self.next;
+				self.next += 1;
+				self.children.retain(|_, signals| !signals.is_closed());
+				let (signals, receiver) = mpsc::unbounded_channel();
+				self.children.insert(id, signals);
+				tokio::spawn(watch(id, child, receiver, channel.clone()));
+				Ok((Reply::Spawned(id, pid), Some(fd)))
+			}
+			Request::Signal(id, signal) => {
+				let signal = Signal::try_from(signal)?;
+				if ![Signal::SIGTERM, Signal::SIGKILL].contains(&signal) {
+					return Err(refused(signal.as_str()));
+				}
+				let sent = self.children.get(&id).is_some_and(|signals| signals.send(signal).is_ok());
+				match sent {
+					true => Ok((Reply::Done, None)),
+					false => Err(std::io::Error::from_raw_os_error(nix::libc::ESRCH)),
+				}
+			}
+			Request::Unlink(Address::Unix(path)) => {
+				if let Some(idx) = self.sockets.iter().position(|socket| *socket == path) {
+					self.sockets.swap_remove(idx);
+					let _ = std::fs::remove_file(&path);
+				}
+				Ok((Reply::Done, None))
+			}
+			Request::Unlink(Address::Inet(_)) => Ok((Reply::Done, None)),
+			Request::Exit => unreachable!("handled by serve()"),
+		}
+	}
+}
+
+/* Checked before each child is started, so that a bad user or chroot
+ * is reported rather than children dying at startup
+ */
+fn check(config: &Config) -> Result<(), proc::PrivError> {
+	if proc::unprivileged() {
+		return Ok(());
+	}
+	let (user, gid) = proc::credentials(&config.user, config.group.as_deref())?;
+	proc::check_chroot(&config.chroot, &user, gid)?;
+	proc::check_chroot(&config.empty, &user, gid)
+}
+
+/* Owns the child until it exits, which is passed on to the parent */
+async fn watch(id: u64, mut child: tokio::process::Child,
+mut signals: mpsc::UnboundedReceiver<Signal>, channel: Arc<proc::Peer>) {
+	let status = loop {
+		tokio::select! {
+			status = child.wait() => break status,
+			Some(signal) = signals.recv() => {
+				if let Some(pid) = child.id() {
+					let pid = nix::unistd::Pid::from_raw(pid as i32);
+					if let Err(err) = nix::sys::signal::kill(pid, signal) {
+						log::warning!("helper: kill {pid}: {err}");
+					}
+				}
+			}
+		}
+	};
+	let status = match status {
+		Ok(status) => status.into_raw(),
+		Err(err) => {
+			log::error!("helper: wait: {err}");
+			ExitStatus::from_raw(nix::libc::SIGKILL).into_raw()
+		}
+	};
+	let buf = serde_cbor::to_vec(&Exited { id, status }).expect("serde");
+	let _ = channel.socket().send(&buf).await;
+}
+
+fn bind(listen: &Listen) -> std::io::Result<OwnedFd> {
+	match &listen.addr {
+		Address::Inet(addr) => bind_inet(*addr),
+		Address::Unix(path) => bind_unix(path, listen)
+			.map_err(|err| std::io::Error::new(err.kind(), format!("{path}: {err}"))),
+	}
+}
+
+/* IPv6 sockets are made IPv6 only, so that the same port can be bound
+ * on both the IPv4 and IPv6 wildcard address
+ */
+fn bind_inet(addr: SocketAddr) -> std::io::Result<OwnedFd> {
+	let socket = match addr {
+		SocketAddr::V4(_) => TcpSocket::new_v4()?,
+		SocketAddr::V6(_) => {
+			let socket = TcpSocket::new_v6()?;
+			setsockopt(&socket, sockopt::Ipv6V6Only, &true)?;
+			socket
+		}
+	};
+	socket.set_reuseaddr(true)?;
+	socket.bind(addr)?;
+	Ok(socket.listen(1024)?.into_std()?.into())
+}
+
+/* A socket left behind by an earlier run is replaced, any other file
+ * is not. Owner and mode are set before it listens, so no connection
+ * comes in with the permissions it was created with
+ */
+fn bind_unix(path: &str, listen: &Listen) -> std::io::Result<OwnedFd> {
+	use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+	match std::fs::symlink_metadata(path) {
+		Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+		Ok(_) => return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists,
+			"exists and is not a socket")),
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+		Err(err) => return Err(err),
+	}
+	let socket = nix::sys::socket::socket(AddressFamily::Unix, SockType::Stream,
+		SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK, None)?;
+	nix::sys::socket::bind(socket.as_raw_fd(), &UnixAddr::new(path)?)?;
+	let permissions = || -> std::io::Result<()> {
+		if let Some(mode) = listen.mode {
+			std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+		}
+		let (user, group) = owner(listen)?;
+		nix::unistd::chown(path, user, group)?;
+		Ok(())
+	};
+	if let Err(err) = permissions() {
+		let _ = std::fs::remove_file(path);
+		return Err(err);
+	}
+	nix::sys::socket::listen(&socket, 1024)?;
+	Ok(socket)
+}
+
+fn owner(listen: &Listen) -> std::io::Result<(Option<nix::unistd::Uid>, Option<nix::unistd::Gid>)> {
+	let missing = |what, name: &str| std::io::Error::new(std::io::ErrorKind::NotFound,
+		format!("no such {what} \"{name}\""));
+	let user = match &listen.owner {
+		Some(name) => Some(nix::unistd::User::from_name(name)?
+			.ok_or_else(|| missing("user", name))?.uid),
+		None => None,
+	};
+	let group = match &listen.group {
+		Some(name) => Some(nix::unistd::Group::from_name(name)?
+			.ok_or_else(|| missing("group", name))?.gid),
+		None => None,
+	};
+	Ok((user, group))
+}
@@ -1,14 +1,26 @@
 use std::collections::HashMap;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, AsyncWrite};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, AsyncRead, AsyncWrite, ReadBuf};
 use std::fmt::Write;
 use thiserror::Error;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Method {
 	GET,
 	HEAD,
 }
 
+impl Method {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Method::GET => "GET",
+			Method::HEAD => "HEAD",
+		}
+	}
+}
+
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Version {
@@ -16,6 +28,15 @@ pub enum Version {
 	OneOne,
 }
 
+impl Version {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Version::One => "HTTP/1.0",
+			Version::OneOne => "HTTP/1.1",
+		}
+	}
+}
+
 struct HttpPath(String);
 
 impl HttpPath {
@@ -25,12 +46,7 @@ impl HttpPath {
 		}
 		let mut ret = Vec::new();
 		let mut chars = str.iter();
-		loop {
-			let char = match chars.next() {
-				Some(c) => c,
-				None => break,
-			};
-
+		while let Some(char) = chars.next() {
 			if *char == b'%' {
 				let mut arr = [0u8; 2];
 				arr[0] = *chars.next()?;
@@ -118,10 +134,11 @@ impl Request {
 			}
 			let (key, value) = line.split_once(':')
 				.ok_or(Error::BadHeader)?;
+			let key = key.to_ascii_lowercase();
 			let values = value.split(',');
 			for value in values {
 				let value = value.trim();
-				headers.entry(key.to_string()).and_modify(|e: &mut Vec<String>| {
+				headers.entry(key.clone()).and_modify(|e: &mut Vec<String>| {
 					e.push(value.to_string());
 				}).or_insert(vec![value.to_string()]);
 			}
@@ -139,6 +156,31 @@ impl Request {
 	pub fn version(&self) -> Version {
 		self.version
 	}
+	pub fn header(&self, name: &str) -> Option<&str> {
+		let values = self.headers.get(&name.to_ascii_lowercase())?;
+		values.first().map(|value| value.as_str())
+	}
+	/* Each value of a header, lists are split at the commas */
+	pub fn header_values(&self, name: &str) -> &[String] {
+		self.headers.get(&name.to_ascii_lowercase()).map_or(&[], |values| values.as_slice())
+	}
+	/* All values of a header as they were sent */
+	pub fn header_line(&self, name: &str) -> Option<String> {
+		let values = self.headers.get(&name.to_ascii_lowercase())?;
+		Some(values.join(", "))
+	}
+
+	/* The Host header without the port */
+	pub fn host(&self) -> Option<&str> {
+		let host = self.header("Host")?;
+		if let Some(rest) = host.strip_prefix('[') {
+			return rest.split_once(']').map(|(host, _)| host);
+		}
+		match host.rsplit_once(':') {
+			Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => Some(host),
+			_ => Some(host),
+		}
+	}
 }
 
 #[derive(Clone, Copy)]
@@ -154,6 +196,54 @@ pub struct Response<'a, T: Content> {
 	version: Version,
 	head: bool,
 	headers: &'a [(&'a str, &'a str)],
+	/* Of the status line and headers */
+	head_len: u64,
+}
+
+/* Counts the bytes written through it. It goes beneath any buffer,
+ * so that only what was handed to the peer is counted
+ */
+pub struct Counted<S> {
+	stream: S,
+	count: u64,
+}
+
+impl <S> Counted<S> {
+	pub fn new(stream: S) -> Self {
+		Self { stream, count: 0 }
+	}
+
+	pub fn count(&self) -> u64 {
+		self.count
+	}
+}
+
+impl <S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+	fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>)
+	-> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.stream).poll_read(cx, buf)
+	}
+}
+
+impl <S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+	fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+	-> Poll<std::io::Result<usize>> {
+		let this = &mut *self;
+		let poll = Pin::new(&mut this.stream).poll_write(cx, buf);
+		if let Poll::Ready(Ok(len)) = poll {
+			this.count += len as u64;
+		}
+		poll
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.stream).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
+	-> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.stream).poll_shutdown(cx)
+	}
 }
 
 impl <'a, T: Content> Response<'a, T> {
@@ -164,36 +254,39 @@ impl <'a, T: Content> Response<'a, T> {
 			content,
 			head,
 			headers,
+			head_len: 0,
 		}
 	}
+	/* Returns the length of the body that was sent */
 	pub async fn write<E: AsyncWriteExt + Unpin + Send> (&mut self, writer: &mut E) 
-	-> std::io::Result<()> {
-		let version = match self.version {
-			Version::One => "HTTP/1.0",
-			Version::OneOne => "HTTP/1.1",
-		};
-		writer.write_all(version.as_bytes()).await?;
-		writer.write_u8(b' ').await?;
-		let response = self.content.code();
-		let response = response.val();
-		writer.write_all(response.as_bytes()).await?;
-		writer.write_all(b"\r\n").await?;
-
+	-> std::io::Result<u64> {
 		let mut str = String::new();
-		write!(str, "Content-Length: {}\r\n", self.content.len().await.expect("idk"))
-			.unwrap();
-		writer.write_all(str.as_bytes()).await?;
+		write!(str, "{} {}\r\n", self.version.as_str(), self.content.code().val()).unwrap();
+
+		let len = self.content.len().await.expect("idk");
+		write!(str, "Content-Length: {len}\r\n").unwrap();
 
 		for (key, value) in self.headers {
-			let mut str = String::new();
 			write!(str, "{key}: {value}\r\n").unwrap();
-			writer.write_all(str.as_bytes()).await?;
 		}
-		writer.write_all(b"\r\n").await?;
-		if !self.head {
-			self.content.write(writer).await?;
+		str.push_str("\r\n");
+		self.head_len = str.len() as u64;
+		writer.write_all(str.as_bytes()).await?;
+		if self.head {
+			return Ok(0);
+		}
+		self.content.write(writer).await?;
+		Ok(len.try_into().unwrap())
+	}
+
+	/* How much of the body is in what reached the peer, given how
+	 * much of the response did
+	 */
+	pub fn sent(&self, delivered: u64) -> u64 {
+		match self.head {
+			true => 0,
+			false => delivered.saturating_sub(self.head_len),
 		}
-		Ok(())
 	}
 }
 
@@ -216,6 +309,15 @@ impl ResponseCode {
 			ResponseCode::InternalError => "500 Internal Server Error",
 		}
 	}
+
+	pub const fn status(&self) -> u16 {
+		match self {
+			ResponseCode::Ok => 200,
+			ResponseCode::NotFound => 404,
+			ResponseCode::PermissionDenied => 403,
+			ResponseCode::InternalError => 500,
+		}
+	}
 }
 
 #[async_trait::async_trait]
@@ -237,7 +339,7 @@ impl Content for ResponseCode {
 #[cfg(test)]
 mod tests {
 	use super::*;
-	use tokio::io::BufReader;
+	use tokio::io::{BufReader, BufWriter};
 	#[tokio::test]
 	async fn header() {
 		let buf = b"GET /%20 HTTP/1.1\r\n";
@@ -252,4 +354,69 @@ mod tests {
 		};
 		assert_eq!(found, wanted);
 	}
+	#[tokio::test]
+	async fn host() {
+		let buf = b"GET / HTTP/1.1\r\nhOsT: example.com:8080\r\n\r\n";
+		let mut reader = BufReader::new(&buf[..]);
+		let found = Request::read(&mut reader).await.unwrap();
+		assert_eq!(found.host(), Some("example.com"));
+
+		let buf = b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n";
+		let mut reader = BufReader::new(&buf[..]);
+		let found = Request::read(&mut reader).await.unwrap();
+		assert_eq!(found.host(), Some("::1"));
+	}
+
+	struct Body(&'static str);
+
+	#[async_trait::async_trait]
+	impl Content for Body {
+		async fn len(&self) -> std::io::Result<usize> {
+			Ok(self.0.len())
+		}
+		async fn write<T: AsyncWrite + Unpin + Send> (&mut self, writer: &mut T)
+		-> std::io::Result<()> {
+			writer.write_all(self.0.as_bytes()).await
+		}
+	}
+
+	/* Takes so many bytes, then fails like a peer that went away */
+	struct Short(usize);
+
+	impl AsyncWrite for Short {
+		fn poll_write(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8])
+		-> Poll<std::io::Result<usize>> {
+			if self.0 == 0 {
+				return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+			}
+			let len = buf.len().min(self.0);
+			self.0 -= len;
+			Poll::Ready(Ok(len))
+		}
+		fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+		fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>)
+		-> Poll<std::io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+	}
+
+	#[tokio::test]
+	async fn sent() {
+		let mut body = Body("hello world");
+		let mut buf = Vec::new();
+		let mut response = Response::new(&mut body, &[], false);
+		assert_eq!(response.write(&mut buf).await.unwrap(), 11);
+		assert_eq!(response.sent(buf.len() as u64), 11);
+
+		/* All of it fits in the buffer, only flushing fails */
+		let head = buf.len() - 11;
+		let mut stream = BufWriter::new(Counted::new(Short(head + 5)));
+		let mut response = Response::new(&mut body, &[], false);
+		response.write(&mut stream).await.unwrap();
+		assert!(stream.flush().await.is_err());
+		assert_eq!(response.sent(stream.get_ref().count()), 5);
+		assert_eq!(response.sent(3), 0);
+	}
 }
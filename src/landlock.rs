@@ -0,0 +1,254 @@
+/* Landlock standing in for unveil(2) on Linux. Paths are collected
+ * as they are unveiled and turned into a ruleset once pledge() drops
+ * the unveil promise, which is when OpenBSD locks them too
+ */
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+	#[error("unveil permissions \"{0}\"")]
+	Permissions(String),
+	#[error("landlock: {0}")]
+	Landlock(std::io::Error),
+	#[error("{path}: {source}")]
+	Path {
+		path: String,
+		source: std::io::Error,
+	},
+}
+
+/* What came of locking the unveiled paths */
+#[derive(Debug, PartialEq)]
+pub enum Status {
+	Enforced,
+	/* Nothing was unveiled, there is nothing to restrict */
+	Unrestricted,
+	Unsupported,
+}
+
+static UNVEILED: Mutex<Vec<(PathBuf, u64)>> = Mutex::new(Vec::new());
+
+const EXECUTE: u64 = 1 << 0;
+const WRITE_FILE: u64 = 1 << 1;
+const READ_FILE: u64 = 1 << 2;
+const READ_DIR: u64 = 1 << 3;
+const REMOVE_DIR: u64 = 1 << 4;
+const REMOVE_FILE: u64 = 1 << 5;
+const MAKE_CHAR: u64 = 1 << 6;
+const MAKE_DIR: u64 = 1 << 7;
+const MAKE_REG: u64 = 1 << 8;
+const MAKE_SOCK: u64 = 1 << 9;
+const MAKE_FIFO: u64 = 1 << 10;
+const MAKE_BLOCK: u64 = 1 << 11;
+const MAKE_SYM: u64 = 1 << 12;
+const REFER: u64 = 1 << 13;
+const TRUNCATE: u64 = 1 << 14;
+const IOCTL_DEV: u64 = 1 << 15;
+
+/* Rights that make sense on a file rather than a directory */
+const FILE: u64 = EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE | IOCTL_DEV;
+
+/* The rights each ABI version knows of, all of them are handled so
+ * that anything not unveiled is denied
+ */
+fn handled(abi: i64) -> u64 {
+	let mut handled = (MAKE_SYM << 1) - 1;
+	if abi >= 2 {
+		handled |= REFER;
+	}
+	if abi >= 3 {
+		handled |= TRUNCATE;
+	}
+	if abi >= 5 {
+		handled |= IOCTL_DEV;
+	}
+	handled
+}
+
+/* Maps unveil permissions, an empty string blocks the path */
+fn access(permissions: &str) -> Result<u64, Error> {
+	let mut access = 0;
+	for c in permissions.chars() {
+		access |= match c {
+			'r' => READ_FILE | READ_DIR,
+			'w' => WRITE_FILE | TRUNCATE,
+			'x' => EXECUTE,
+			'c' => REMOVE_DIR | REMOVE_FILE | MAKE_CHAR | MAKE_DIR | MAKE_REG | MAKE_SOCK
+				| MAKE_FIFO | MAKE_BLOCK | MAKE_SYM | REFER,
+			_ => return Err(Error::Permissions(permissions.to_string())),
+		};
+	}
+	Ok(access)
+}
+
+pub fn unveil(path: impl AsRef<Path>, permissions: &str) -> Result<(), Error> {
+	let access = access(permissions)?;
+	UNVEILED.lock().unwrap().push((path.as_ref().to_path_buf(), access));
+	Ok(())
+}
+
+/* Works out the rules for the unveiled paths, a path unveiled more
+ * than once gets all of the access, one that a directory above it
+ * already grants gets no rule of its own.
+ *
+ * Landlock can only grant, unlike unveil() it cannot take a path
+ * beneath an allowed one away again. Such blocked paths are returned
+ * apart, they are only denied by the checks of httpd itself
+ */
+fn rules(unveiled: &[(PathBuf, u64)]) -> (Vec<(PathBuf, u64)>, Vec<PathBuf>) {
+	let mut granted: Vec<(PathBuf, u64)> = Vec::new();
+	for (path, access) in unveiled.iter().filter(|(_, access)| *access != 0) {
+		match granted.iter_mut().find(|(other, _)| other == path) {
+			Some((_, other)) => *other |= access,
+			None => granted.push((path.clone(), *access)),
+		}
+	}
+	let rules = granted.iter()
+		.filter(|(path, access)| !granted.iter().any(|(other, above)| {
+			other != path && path.starts_with(other) && above & access == *access
+		}))
+		.cloned()
+		.collect();
+	let unenforced = unveiled.iter()
+		.filter(|(path, access)| *access == 0 && granted.iter().any(|(other, _)| path.starts_with(other)))
+		.map(|(path, _)| path.clone())
+		.collect();
+	(rules, unenforced)
+}
+
+mod sys {
+	use nix::libc;
+	use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+	use std::os::unix::fs::OpenOptionsExt;
+	use std::path::Path;
+
+	const CREATE_RULESET_VERSION: u32 = 1 << 0;
+	const RULE_PATH_BENEATH: u32 = 1;
+
+	#[repr(C)]
+	struct RulesetAttr {
+		handled_access_fs: u64,
+	}
+
+	#[repr(C, packed)]
+	struct PathBeneathAttr {
+		allowed_access: u64,
+		parent_fd: i32,
+	}
+
+	/* None when the kernel was built without landlock or it is
+	 * turned off
+	 */
+	pub fn abi() -> Option<i64> {
+		let abi = unsafe {
+			libc::syscall(libc::SYS_landlock_create_ruleset, std::ptr::null::<RulesetAttr>(), 0,
+				CREATE_RULESET_VERSION)
+		};
+		(abi > 0).then_some(abi)
+	}
+
+	pub fn ruleset(handled: u64) -> std::io::Result<OwnedFd> {
+		let attr = RulesetAttr { handled_access_fs: handled };
+		let fd = unsafe {
+			libc::syscall(libc::SYS_landlock_create_ruleset, &attr,
+				std::mem::size_of::<RulesetAttr>(), 0)
+		};
+		if fd < 0 {
+			return Err(std::io::Error::last_os_error());
+		}
+		Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+	}
+
+	pub fn add_rule(ruleset: &OwnedFd, path: &Path, access: u64) -> std::io::Result<()> {
+		let file = std::fs::OpenOptions::new().read(true)
+			.custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+			.open(path)?;
+		let access = match file.metadata()?.is_dir() {
+			true => access,
+			false => access & super::FILE,
+		};
+		let attr = PathBeneathAttr { allowed_access: access, parent_fd: file.as_raw_fd() };
+		let ret = unsafe {
+			libc::syscall(libc::SYS_landlock_add_rule, ruleset.as_raw_fd(), RULE_PATH_BENEATH,
+				&attr, 0)
+		};
+		if ret < 0 {
+			return Err(std::io::Error::last_os_error());
+		}
+		Ok(())
+	}
+
+	/* Only the calling thread and those it starts later are
+	 * restricted, this is done before tokio starts any
+	 */
+	pub fn restrict(ruleset: &OwnedFd) -> std::io::Result<()> {
+		if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+			return Err(std::io::Error::last_os_error());
+		}
+		if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } < 0 {
+			return Err(std::io::Error::last_os_error());
+		}
+		Ok(())
+	}
+}
+
+/* Restricts the process to what was unveiled */
+pub fn lock() -> Result<Status, Error> {
+	let unveiled = std::mem::take(&mut *UNVEILED.lock().unwrap());
+	if unveiled.is_empty() {
+		return Ok(Status::Unrestricted);
+	}
+	let Some(abi) = sys::abi() else {
+		return Ok(Status::Unsupported);
+	};
+	let handled = handled(abi);
+	let ruleset = sys::ruleset(handled).map_err(Error::Landlock)?;
+	let (rules, unenforced) = rules(&unveiled);
+	for path in unenforced {
+		crate::log::warning!("{}: blocked beneath an unveiled path, landlock cannot deny it",
+			path.display());
+	}
+	for (path, access) in rules {
+		match sys::add_rule(&ruleset, &path, access & handled) {
+			Ok(()) => {}
+			/* unveil() takes paths that do not exist yet as well */
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+			Err(source) => return Err(Error::Path { path: path.display().to_string(), source }),
+		}
+	}
+	sys::restrict(&ruleset).map_err(Error::Landlock)?;
+	Ok(Status::Enforced)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[test]
+	fn rules() {
+		let dir = PathBuf::from("/var/www/htdocs");
+		let read = READ_FILE | READ_DIR;
+		let unveiled = vec![
+			(dir.clone(), access("r").unwrap()),
+			(dir.join("private"), access("").unwrap()),
+			(dir.join("public"), access("r").unwrap()),
+			(dir.join("uploads"), access("rwc").unwrap()),
+			(PathBuf::from("/etc/ssl"), access("").unwrap()),
+		];
+		let (rules, unenforced) = super::rules(&unveiled);
+		assert_eq!(rules, vec![(dir.clone(), read), (dir.join("uploads"), access("rwc").unwrap())]);
+		assert_eq!(unenforced, vec![dir.join("private")]);
+
+		/* Several servers with the same root */
+		let unveiled = vec![
+			(dir.join("a"), access("r").unwrap()),
+			(dir.join("a"), access("w").unwrap()),
+			(dir.join("b"), access("").unwrap()),
+		];
+		let (rules, unenforced) = super::rules(&unveiled);
+		assert_eq!(rules, vec![(dir.join("a"), access("rw").unwrap())]);
+		assert!(unenforced.is_empty());
+		assert!(access("rq").is_err());
+	}
+}
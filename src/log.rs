@@ -0,0 +1,233 @@
+use crate::{logger, proc};
+use std::fmt;
+use std::io::Write;
+use std::os::fd::{AsFd, BorrowedFd, FromRawFd};
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Mutex, OnceLock};
+use nix::sys::stat::{fstat, SFlag};
+
+const SYSLOG: &str = "/dev/log";
+/* LOG_DAEMON */
+const FACILITY: u8 = 3 << 3;
+
+/* The syslog severities, most severe first, httpd itself never
+ * logs anything above crit
+ */
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+	Emerg,
+	Alert,
+	Crit,
+	Err,
+	Warning,
+	Notice,
+	Info,
+	Debug,
+}
+
+impl Level {
+	/* Messages up to notice are logged, each -v adds a level */
+	pub fn from_verbose(verbose: u8) -> Self {
+		match verbose {
+			0 => Level::Notice,
+			1 => Level::Info,
+			_ => Level::Debug,
+		}
+	}
+
+	fn verbose(self) -> u8 {
+		(self as u8).saturating_sub(Level::Notice as u8)
+	}
+}
+
+/* Where the error log goes when not running in the foreground */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+	Syslog,
+	File(String),
+}
+
+enum Output {
+	Stderr,
+	/* The syslog socket, or in the children the parent collecting
+	 * lines for a file
+	 */
+	Datagram(UnixDatagram),
+	File(Mutex<std::fs::File>, String),
+}
+
+struct Log {
+	role: String,
+	level: Level,
+	output: Output,
+	/* Given to the children as their error log */
+	child: Option<UnixDatagram>,
+	/* Read by collect(), once there is a runtime */
+	collector: Option<UnixDatagram>,
+}
+
+static LOG: OnceLock<Log> = OnceLock::new();
+
+/* Sets up the error log of the parent, without a target everything
+ * goes to stderr. The target is only read at startup, changing it
+ * takes a restart
+ */
+pub fn init(role: &str, level: Level, target: Option<&Target>) -> std::io::Result<()> {
+	let (output, child, collector) = match target {
+		None => (Output::Stderr, None, None),
+		Some(Target::Syslog) => {
+			let socket = UnixDatagram::unbound()?;
+			socket.connect(SYSLOG)
+				.map_err(|err| std::io::Error::new(err.kind(), format!("{SYSLOG}: {err}")))?;
+			socket.set_nonblocking(true)?;
+			let child = socket.try_clone()?;
+			(Output::Datagram(socket), Some(child), None)
+		}
+		Some(Target::File(path)) => {
+			let file = open(path)?;
+			let (collector, child) = UnixDatagram::pair()?;
+			collector.set_nonblocking(true)?;
+			child.set_nonblocking(true)?;
+			(Output::File(Mutex::new(file), path.clone()), Some(child), Some(collector))
+		}
+	};
+	set(Log { role: role.to_string(), level, output, child, collector });
+	Ok(())
+}
+
+/* Children write to the error log the parent gave them, or to stderr
+ * when running in the foreground
+ */
+pub fn init_child(role: &str, level: Level) {
+	let socket = fstat(proc::LOG_FD).ok()
+		.filter(|stat| SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFSOCK)
+		.map(|_| unsafe { UnixDatagram::from_raw_fd(proc::LOG_FD) });
+	let output = match socket {
+		Some(socket) => Output::Datagram(socket),
+		None => Output::Stderr,
+	};
+	set(Log { role: role.to_string(), level, output, child: None, collector: None });
+}
+
+fn set(log: Log) {
+	if LOG.set(log).is_err() {
+		return;
+	}
+	std::panic::set_hook(Box::new(|info| write(Level::Crit, format_args!("{info}"))));
+}
+
+fn open(path: &str) -> std::io::Result<std::fs::File> {
+	std::fs::OpenOptions::new().append(true).create(true).open(path)
+		.map_err(|err| std::io::Error::new(err.kind(), format!("{path}: {err}")))
+}
+
+pub fn level() -> Level {
+	LOG.get().map_or(Level::Notice, |log| log.level)
+}
+
+/* The number of -v the children are started with */
+pub fn verbose() -> u8 {
+	level().verbose()
+}
+
+pub fn child_fd() -> Option<BorrowedFd<'static>> {
+	LOG.get()?.child.as_ref().map(|child| child.as_fd())
+}
+
+pub fn write(level: Level, args: fmt::Arguments) {
+	let Some(log) = LOG.get() else {
+		eprintln!("httpd: {args}");
+		return;
+	};
+	if level > log.level {
+		return;
+	}
+	match &log.output {
+		Output::Stderr => eprintln!("httpd: {}: {args}", log.role),
+		/* Messages are dropped rather than waited on when syslog is
+		 * not keeping up
+		 */
+		Output::Datagram(socket) => {
+			let line = format!("<{}>httpd: {}: {args}", FACILITY | level as u8, log.role);
+			let _ = socket.send(line.as_bytes());
+		}
+		Output::File(file, _) => {
+			let line = format!("httpd: {}: {args}", log.role);
+			write_file(file, &line);
+		}
+	}
+}
+
+fn write_file(file: &Mutex<std::fs::File>, line: &str) {
+	let time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+		.map_or(0, |time| time.as_secs());
+	let (year, month, day, hour, min, sec) = logger::civil(time);
+	let line = format!("{year}-{month:02}-{day:02}T{hour:02}:{min:02}:{sec:02}Z {line}\n");
+	let _ = file.lock().unwrap().write_all(line.as_bytes());
+}
+
+/* Writes the lines the children send to the log file, never
+ * completes when the error log is not a file
+ */
+pub async fn collect() {
+	let Some(Log { collector: Some(collector), output: Output::File(file, _), .. }) = LOG.get()
+	else {
+		return std::future::pending().await;
+	};
+	let collector = match collector.try_clone().and_then(tokio::net::UnixDatagram::from_std) {
+		Ok(collector) => collector,
+		Err(err) => {
+			write(Level::Err, format_args!("error log: {err}"));
+			return std::future::pending().await;
+		}
+	};
+	let mut buf = vec![0u8; 8192];
+	loop {
+		let Ok(len) = collector.recv(&mut buf).await else {
+			continue;
+		};
+		let line = String::from_utf8_lossy(&buf[..len]);
+		/* The priority was already used to filter */
+		let line = match line.split_once('>') {
+			Some((pri, line)) if pri.starts_with('<') => line,
+			_ => &line,
+		};
+		write_file(file, line);
+	}
+}
+
+/* The log file, when logging to one */
+pub fn file() -> Option<&'static str> {
+	match LOG.get() {
+		Some(Log { output: Output::File(_, path), .. }) => Some(path),
+		_ => None,
+	}
+}
+
+/* Switches to the log file opened again, for when it was rotated */
+pub fn reopen(new: std::fs::File) {
+	if let Some(Log { output: Output::File(file, _), .. }) = LOG.get() {
+		*file.lock().unwrap() = new;
+	}
+}
+
+macro_rules! crit {
+	($($arg:tt)*) => { $crate::log::write($crate::log::Level::Crit, format_args!($($arg)*)) };
+}
+macro_rules! error {
+	($($arg:tt)*) => { $crate::log::write($crate::log::Level::Err, format_args!($($arg)*)) };
+}
+macro_rules! warning {
+	($($arg:tt)*) => { $crate::log::write($crate::log::Level::Warning, format_args!($($arg)*)) };
+}
+macro_rules! notice {
+	($($arg:tt)*) => { $crate::log::write($crate::log::Level::Notice, format_args!($($arg)*)) };
+}
+macro_rules! info {
+	($($arg:tt)*) => { $crate::log::write($crate::log::Level::Info, format_args!($($arg)*)) };
+}
+macro_rules! debug {
+	($($arg:tt)*) => { $crate::log::write($crate::log::Level::Debug, format_args!($($arg)*)) };
+}
+pub(crate) use {crit, error, warning, notice, info, debug};
@@ -0,0 +1,284 @@
+use crate::{client, log, proc};
+use proc::pledge;
+use serde_derive::{Serialize, Deserialize};
+use tokio_seqpacket::UnixSeqpacket;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinSet;
+use std::io::Write;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Style {
+	Common,
+	Combined,
+	Json,
+}
+
+impl fmt::Display for Style {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Style::Common => write!(f, "common"),
+			Style::Combined => write!(f, "combined"),
+			Style::Json => write!(f, "json"),
+		}
+	}
+}
+
+/* servers has an entry for each server, the index of the file it logs
+ * to and in which style, the files follow the configuration one by one
+ */
+#[derive(Serialize, Deserialize)]
+pub struct LoggerConfig {
+	pub servers: Vec<Option<(usize, Style)>>,
+	pub files: usize,
+}
+
+/* Messages from the parent, each one carries a file descriptor */
+#[derive(Serialize, Deserialize)]
+pub enum Message {
+	/* A channel to a client process */
+	Client,
+	/* The log file with this index was reopened */
+	Reopen(usize),
+}
+
+/* A request as answered by a client process */
+#[derive(Serialize, Deserialize)]
+pub struct Record {
+	pub server: usize,
+	pub remote: client::Remote,
+	/* As the client reached the first proxy, when it is known */
+	pub https: bool,
+	pub time: SystemTime,
+	pub method: String,
+	pub path: String,
+	pub version: String,
+	pub status: u16,
+	pub bytes: u64,
+	pub referer: Option<String>,
+	pub user_agent: Option<String>,
+	pub duration: Duration,
+}
+
+struct Logs {
+	servers: Vec<Option<(usize, Style)>>,
+	files: RwLock<Vec<std::fs::File>>,
+}
+
+pub async fn main() -> ! {
+	pledge("stdio recvfd", None).expect("pledge");
+	let parent = unsafe {
+		proc::Peer::get_parent()
+	};
+
+	let config: LoggerConfig = {
+		let mut buf = vec![0u8; 65536];
+		let len = parent.socket().recv(&mut buf).await.expect("read");
+		serde_cbor::from_slice(&buf[..len]).expect("serde")
+	};
+	let mut files = Vec::new();
+	for _ in 0..config.files {
+		let (_, fd) = parent.recv_with_fd(&mut []).await.expect("no file descriptor");
+		files.push(std::fs::File::from(fd));
+	}
+	let logs = Arc::new(Logs { servers: config.servers, files: RwLock::new(files) });
+
+	/* Like the filesystem process, this one exits once the parent and
+	 * every client have closed their channels
+	 */
+	let _sigterm = signal(SignalKind::terminate()).expect("sigaction");
+
+	let mut clients = JoinSet::new();
+	let mut parent_open = true;
+	let mut buf = [0u8; 16];
+	loop {
+		tokio::select! {
+			resp = parent.recv_with_fd(&mut buf), if parent_open => match resp {
+				Ok((len, fd)) => match serde_cbor::from_slice(&buf[..len]) {
+					Ok(Message::Client) => {
+						let seq = UnixSeqpacket::try_from(fd).unwrap();
+						let peer = proc::Peer::from_stream(seq);
+						clients.spawn(serve(peer, logs.clone()));
+					}
+					/* The old file is closed as it is replaced */
+					Ok(Message::Reopen(idx)) => {
+						let mut files = logs.files.write().unwrap();
+						if let Some(file) = files.get_mut(idx) {
+							*file = std::fs::File::from(fd);
+						}
+					}
+					Err(err) => {
+						log::crit!("parent sent bad message: {err}");
+						std::process::exit(1);
+					}
+				},
+				Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => parent_open = false,
+				Err(err) => {
+					log::crit!("parent sent bad channel: {err}");
+					std::process::exit(1);
+				}
+			},
+			Some(_) = clients.join_next() => {},
+			else => break,
+		}
+	}
+
+	std::process::exit(0);
+}
+
+async fn serve(peer: proc::Peer, logs: Arc<Logs>) {
+	let mut buf = vec![0u8; 65536];
+	loop {
+		let len = match peer.socket().recv(&mut buf).await {
+			/* The client process has gone away */
+			Ok(0) => break,
+			Ok(len) => len,
+			Err(err) => {
+				log::error!("{err}");
+				break;
+			}
+		};
+		match serde_cbor::from_slice(&buf[..len]) {
+			Ok(record) => logs.write(&record),
+			Err(err) => log::warning!("bad log record: {err}"),
+		}
+	}
+}
+
+impl Logs {
+	/* Each record is written with a single write to a file opened
+	 * for appending, so lines from different clients do not mix
+	 */
+	fn write(&self, record: &Record) {
+		let Some(Some((file, style))) = self.servers.get(record.server) else {
+			return;
+		};
+		let line = record.format(*style);
+		let files = self.files.read().unwrap();
+		if let Err(err) = (&files[*file]).write_all(line.as_bytes()) {
+			log::error!("access log: {err}");
+		}
+	}
+}
+
+impl Record {
+	pub fn format(&self, style: Style) -> String {
+		let time = self.time.duration_since(SystemTime::UNIX_EPOCH)
+			.map_or(0, |time| time.as_secs());
+		let (year, month, day, hour, min, sec) = civil(time);
+		match style {
+			Style::Common | Style::Combined => {
+				const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+					"Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+				let mut line = format!("{} - - [{day:02}/{}/{year}:{hour:02}:{min:02}:{sec:02} +0000] \
+					\"{} {} {}\" {} {}", self.remote.host(), MONTHS[month as usize - 1],
+					self.method, Escaped(&self.path), self.version, self.status, self.bytes);
+				if style == Style::Combined {
+					let field = |value: &Option<String>| value.as_deref()
+						.map_or("-".to_string(), |value| Escaped(value).to_string());
+					line.push_str(&format!(" \"{}\" \"{}\"",
+						field(&self.referer), field(&self.user_agent)));
+				}
+				line.push('\n');
+				line
+			}
+			Style::Json => {
+				let field = |value: &Option<String>| value.as_deref()
+					.map_or("null".to_string(), |value| Json(value).to_string());
+				let scheme = if self.https { "https" } else { "http" };
+				format!("{{\"remote\":{},\"scheme\":\"{scheme}\",\
+					\"time\":\"{year}-{month:02}-{day:02}T{hour:02}:{min:02}:{sec:02}Z\",\
+					\"method\":{},\"path\":{},\"version\":{},\"status\":{},\"bytes\":{},\
+					\"referer\":{},\"user_agent\":{},\"duration\":{:.6}}}\n",
+					Json(&self.remote.host()), Json(&self.method), Json(&self.path),
+					Json(&self.version), self.status, self.bytes, field(&self.referer),
+					field(&self.user_agent), self.duration.as_secs_f64())
+			}
+		}
+	}
+}
+
+/* Converts seconds since the epoch to a UTC date and time */
+pub fn civil(time: u64) -> (u64, u64, u64, u64, u64, u64) {
+	let (days, secs) = (time / 86400, time % 86400);
+	/* Days are counted from 0000-03-01 so that leap days come last */
+	let days = days + 719468;
+	let era = days / 146097;
+	let doe = days % 146097;
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + u64::from(month <= 2);
+	(year, month, day, secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+/* Quotes and unprintable characters are escaped inside the quoted
+ * fields of the common log format
+ */
+struct Escaped<'a>(&'a str);
+
+impl fmt::Display for Escaped<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for c in self.0.chars() {
+			match c {
+				'"' | '\\' => write!(f, "\\{c}")?,
+				c if c.is_control() => write!(f, "\\x{:02x}", c as u32)?,
+				c => write!(f, "{c}")?,
+			}
+		}
+		Ok(())
+	}
+}
+
+struct Json<'a>(&'a str);
+
+impl fmt::Display for Json<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "\"")?;
+		for c in self.0.chars() {
+			match c {
+				'"' | '\\' => write!(f, "\\{c}")?,
+				c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+				c => write!(f, "{c}")?,
+			}
+		}
+		write!(f, "\"")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[test]
+	fn format() {
+		let record = Record {
+			server: 0,
+			remote: client::Remote::Inet("192.0.2.1:4711".parse().unwrap()),
+			https: true,
+			time: SystemTime::UNIX_EPOCH + Duration::from_secs(971186136),
+			method: "GET".to_string(),
+			path: "/a \"b\"".to_string(),
+			version: "HTTP/1.1".to_string(),
+			status: 200,
+			bytes: 2326,
+			referer: None,
+			user_agent: Some("curl/8.5.0".to_string()),
+			duration: Duration::from_millis(15),
+		};
+		assert_eq!(record.format(Style::Common), "192.0.2.1 - - [10/Oct/2000:13:55:36 +0000] \
+			\"GET /a \\\"b\\\" HTTP/1.1\" 200 2326\n");
+		assert_eq!(record.format(Style::Combined), "192.0.2.1 - - [10/Oct/2000:13:55:36 +0000] \
+			\"GET /a \\\"b\\\" HTTP/1.1\" 200 2326 \"-\" \"curl/8.5.0\"\n");
+		assert_eq!(record.format(Style::Json), "{\"remote\":\"192.0.2.1\",\"scheme\":\"https\",\
+			\"time\":\"2000-10-10T13:55:36Z\",\"method\":\"GET\",\"path\":\"/a \\\"b\\\"\",\
+			\"version\":\"HTTP/1.1\",\"status\":200,\"bytes\":2326,\"referer\":null,\
+			\"user_agent\":\"curl/8.5.0\",\"duration\":0.015000}\n");
+
+		let record = Record { remote: client::Remote::Unix, ..record };
+		assert!(record.format(Style::Common).starts_with("unix: - - "));
+	}
+}
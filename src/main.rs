@@ -1,4 +1,5 @@
 mod proc;
+mod config;
 mod http;
 mod tls;
 mod mime;
@@ -6,106 +7,602 @@ mod mime;
 mod fs;
 mod client;
 mod crypto;
+mod logger;
+mod log;
+mod seccomp;
+mod activation;
+mod network;
+mod proxy;
+mod forwarded;
+mod control;
+mod metrics;
+mod helper;
+#[cfg(target_os = "linux")]
+mod landlock;
+#[cfg(target_os = "linux")]
+mod namespace;
+#[cfg(target_os = "linux")]
+mod caps;
 
 use tokio_seqpacket::UnixSeqpacket;
 use proc::pledge;
-use tokio::net::{UnixStream, TcpListener};
+use tokio::net::{UnixListener, UnixStream, TcpListener};
 use tokio::signal::unix::{signal, SignalKind};
-use tokio_util::sync::CancellationToken;
+use tokio::task::JoinHandle;
 use std::os::fd::OwnedFd;
+use std::task::Poll;
+use std::time::Duration;
+use std::collections::HashMap;
+use std::io::Seek;
+use std::cell::Cell;
+use std::process::ExitCode;
+use std::sync::Arc;
+use tokio::time::Instant;
 
 use client::ClientConfig;
+use crypto::CryptoConfig;
+use logger::LoggerConfig;
+use config::{Address, Config};
+use log::Level;
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-	let mut args = std::env::args();
-	args.next().expect("what?");
-	if let Some(arg) = args.next() {
-		if arg == "-p" {
-			let arg = args.next().unwrap();
-			match arg.as_str() {
-				"client" => {
-					proc::privdrop("/var/empty", "www").expect("privdrop");
-					client::main().await;
-				}
-				"crypto" => {
-					proc::privdrop("/var/empty", "www").expect("privdrop");
-					crypto::main().await;
-				}
-				"filesystem" => {
-					proc::privdrop("/var/www/htdocs/", "www").expect("privdrop");
-					fs::main().await;
+fn main() -> ExitCode {
+	let mut options = match Options::parse(std::env::args().skip(1)) {
+		Ok(options) => options,
+		Err(err) => {
+			eprintln!("httpd: {err}");
+			usage();
+		}
+	};
+
+	/* Talks to the running server rather than being part of it */
+	if options.process.as_deref() == Some("ctl") {
+		let config = match Config::load(&options.config) {
+			Ok(config) => config,
+			Err(err) => {
+				eprintln!("{err}");
+				std::process::exit(1);
+			}
+		};
+		let Some(path) = &config.control else {
+			eprintln!("httpd: {}: no control socket", options.config);
+			std::process::exit(1);
+		};
+		runtime().block_on(control::main(path, &options.command));
+	}
+
+	if let Some(process) = &options.process {
+		let (Some(user), Some(chroot)) = (&options.user, &options.chroot) else {
+			usage();
+		};
+		if !["client", "crypto", "filesystem", "logger"].contains(&process.as_str()) {
+			usage();
+		}
+		match options.seccomp.as_deref() {
+			None | Some("kill") => {}
+			Some("log") => seccomp::set_mode(seccomp::Mode::Log),
+			Some(_) => usage(),
+		}
+		log::init_child(process, Level::from_verbose(options.verbose));
+		/* Entering namespaces takes root, not just the capabilities
+		 * the parent hands down
+		 */
+		#[cfg(target_os = "linux")]
+		let root = nix::unistd::geteuid().is_root();
+		#[cfg(target_os = "linux")]
+		let isolated = match options.unprivileged || !root {
+			true => namespace::Isolated::Child,
+			false => {
+				let (isolated, failed) = namespace::isolate();
+				for (namespace, err) in failed {
+					log::warning!("{namespace} namespace: {err}");
 				}
-				_ => {},
+				isolated
+			}
+		};
+		if options.unprivileged {
+			proc::set_unprivileged();
+		}
+		else if let Err(err) = proc::privdrop(chroot, user, options.group.as_deref()) {
+			log::crit!("{err}");
+			std::process::exit(1);
+		}
+		/* Changing between users other than root keeps capabilities */
+		#[cfg(target_os = "linux")]
+		if !options.unprivileged && !root {
+			if let Err(err) = caps::drop_all() {
+				log::crit!("{err}");
+				std::process::exit(1);
 			}
 		}
+		#[cfg(target_os = "linux")]
+		if let namespace::Isolated::Reaper(child) = isolated {
+			namespace::reap(child);
+		}
+		runtime().block_on(async {
+			match process.as_str() {
+				"client" => client::main().await,
+				"crypto" => crypto::main().await,
+				"filesystem" => fs::main(chroot).await,
+				_ => logger::main().await,
+			}
+		});
 	}
 
-	let current_exe = std::env::current_exe().unwrap();
-	let current_exe = current_exe.into_os_string().into_string().unwrap();
+	let config = match Config::load(&options.config) {
+		Ok(config) => config,
+		Err(err) => {
+			eprintln!("{err}");
+			std::process::exit(1);
+		}
+	};
 
-	let global_config = GlobalConfig::new("/usr/share/misc/mime.types").await.unwrap();
+	if options.unprivileged {
+		proc::set_unprivileged();
+	}
 
-	let config = ManagerConfig {
-		/*
-		tls: Some(
-			TlsConfig {
-				cert: "./cert.pem",
-				key: "./key.pem",
-			}
-		),
-		*/
-		tls: None,
-		fs: fs::Server::new(vec![
-			fs::Location::new("/", false),
-			fs::Location::new("/private/", true),
-		]),
-		addr: "127.0.0.1:199".parse().unwrap(),
+	/* Checking takes no privileges and leaves the sockets from the
+	 * service manager for the run that serves
+	 */
+	if options.check {
+		print!("{config}");
+		eprintln!("configuration OK");
+		std::process::exit(0);
+	}
+
+	/* Sockets from the service manager are taken over before the
+	 * process id changes on detaching
+	 */
+	let sockets = match activation::sockets() {
+		Ok(sockets) => sockets,
+		Err(err) => {
+			eprintln!("httpd: {err}");
+			std::process::exit(1);
+		}
 	};
-	let server = Manager::new(&current_exe, config, global_config).await.unwrap();
-
-	proc::privdrop("/var/empty/", "www").expect("privdrop");
-	pledge("stdio sendfd proc inet", None).expect("pledge");
-
-	let token = CancellationToken::new();
-	let mytok = token.clone();
-	tokio::spawn(async move {
-		loop {
-			tokio::select! {
-				err = server.serve() => {
-					if let Err(err) = err {
-						eprintln!("{:?}", err);
-					}
-				}
-				_ = mytok.cancelled() => break,
+	let (adopted, unused) = activation::adopt(sockets, &config.listeners());
+	for socket in unused {
+		eprintln!("httpd: socket {socket} matches no listener, closing it");
+	}
+	let notify = match activation::Notify::from_env() {
+		Ok(notify) => notify,
+		Err(err) => {
+			eprintln!("httpd: {err}");
+			std::process::exit(1);
+		}
+	};
+
+	#[cfg(target_os = "linux")]
+	if capabilities(&options) {
+		/* The helper signals children that run as another user,
+		 * every process empties its bounding set
+		 */
+		let mut needed = vec![
+			caps::CAP_SETGID, caps::CAP_SETUID, caps::CAP_SYS_CHROOT, caps::CAP_KILL,
+			caps::CAP_SETPCAP,
+		];
+		let bound: Vec<_> = config.listeners().into_iter()
+			.filter(|listen| !adopted.contains_key(&listen.addr))
+			.collect();
+		if bound.iter().any(|listen| matches!(listen.addr, Address::Inet(addr) if addr.port() < 1024)) {
+			needed.push(caps::CAP_NET_BIND_SERVICE);
+		}
+		if bound.iter().any(|listen| listen.owner.is_some() || listen.group.is_some()) {
+			needed.push(caps::CAP_CHOWN);
+		}
+		if let Err(err) = caps::check(&needed) {
+			eprintln!("httpd: {err}");
+			std::process::exit(1);
+		}
+	}
+
+	/* Detaching is done before the runtime exists, only the thread
+	 * calling fork() would carry over to the child. The pidfile is
+	 * taken while errors can still be seen on the terminal
+	 */
+	let pidfile = if options.debug {
+		None
+	}
+	else {
+		/* Reloading reads the configuration again from "/" */
+		match std::path::absolute(&options.config) {
+			Ok(path) => options.config = path.to_string_lossy().into_owned(),
+			Err(err) => {
+				eprintln!("httpd: {}: {err}", options.config);
+				std::process::exit(1);
+			}
+		}
+		if let Err(err) = proc::daemonize() {
+			eprintln!("httpd: daemon: {err}");
+			std::process::exit(1);
+		}
+		let pidfile = match proc::Pidfile::create(&config.pidfile) {
+			Ok(pidfile) => pidfile,
+			Err(err) => {
+				eprintln!("httpd: {}: {err}", config.pidfile);
+				std::process::exit(1);
 			}
 		};
-		if let Err(err) = server.end().await {
-			eprintln!("{err}");
+		if let Err(err) = proc::detach_stdio() {
+			eprintln!("httpd: /dev/null: {err}");
+			return ExitCode::FAILURE;
 		}
-	});
+		Some(pidfile)
+	};
+
+	/* In the foreground everything goes to stderr */
+	let level = Level::from_verbose(options.verbose);
+	let target = (!options.debug).then_some(&config.error_log);
+	if let Err(err) = log::init("parent", level, target) {
+		log::init("parent", level, None).expect("log");
+		log::warning!("{err}, logging to stderr");
+	}
+
+	/* Forked before the runtime exists as well. The helper removes
+	 * the pidfile however the server stops
+	 */
+	let channel = match helper::fork() {
+		Ok(helper::Fork::Parent(channel)) => channel,
+		Ok(helper::Fork::Helper(channel)) => {
+			drop((adopted, notify));
+			let current_exe = std::env::current_exe().unwrap();
+			let current_exe = current_exe.into_os_string().into_string().unwrap();
+			helper::main(channel, current_exe, options.config, config, pidfile);
+		}
+		Err(err) => {
+			log::crit!("fork: {err}");
+			return ExitCode::FAILURE;
+		}
+	};
+	if let Some(pidfile) = pidfile {
+		pidfile.disown();
+	}
+	runtime().block_on(parent(&options, config, channel, adopted, notify))
+}
+
+/* Started by an ordinary user, what takes root has to be granted
+ * as capabilities. The helper keeps them for as long as the server
+ * runs, the parent drops them with its user
+ */
+#[cfg(target_os = "linux")]
+fn capabilities(options: &Options) -> bool {
+	!options.unprivileged && !nix::unistd::geteuid().is_root()
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+	tokio::runtime::Builder::new_current_thread()
+		.enable_all()
+		.build()
+		.expect("runtime")
+}
+
+/* Serves from the empty chroot as the user of the children, what
+ * takes privileges is asked of the helper
+ */
+async fn parent(options: &Options, config: Config, channel: OwnedFd,
+adopted: HashMap<Address, OwnedFd>, notify: Option<activation::Notify>) -> ExitCode {
+	log::info!("loaded configuration from {}", options.config);
+	tokio::spawn(log::collect());
+
+	let helper = match helper::Helper::new(channel) {
+		Ok(helper) => helper,
+		Err(err) => {
+			log::crit!("helper: {err}");
+			return ExitCode::FAILURE;
+		}
+	};
+	let mut exits = helper.exits();
+
+	/* Allowed on the control socket besides root */
+	let owner = nix::unistd::getuid().as_raw();
+	#[cfg(target_os = "linux")]
+	let capabilities = capabilities(options);
+	if !options.unprivileged {
+		if let Err(err) = proc::privdrop(&config.empty, &config.user, config.group.as_deref()) {
+			log::crit!("{err}");
+			return ExitCode::FAILURE;
+		}
+	}
 
-	let mut sigchld = signal(SignalKind::child()).expect("signal");
+	let mut server = match Manager::new(helper.clone(), config, adopted).await {
+		Ok(server) => server,
+		Err(err) => {
+			log::crit!("{err}");
+			return ExitCode::FAILURE;
+		}
+	};
+
+	/* Changing between users other than root keeps capabilities,
+	 * they go once the children run
+	 */
+	#[cfg(target_os = "linux")]
+	if capabilities {
+		if let Err(err) = caps::drop_all().and_then(|()| caps::verify_empty()) {
+			log::crit!("{err}");
+			return ExitCode::FAILURE;
+		}
+		log::info!("dropped all capabilities");
+	}
+
+	print_listeners(server.config());
+
+	/* The server runs without a control socket it cannot bind */
+	let control = server.config().control.clone();
+	let (requests, mut commands) = tokio::sync::mpsc::channel(16);
+	let acceptor = match control {
+		Some(_) => helper.control().await
+			.and_then(|socket| control::Server::new(socket, owner))
+			.map_err(|err| log::warning!("control socket: {err}"))
+			.ok(),
+		None => None,
+	};
+	let acceptor = acceptor.map(|control| tokio::spawn(control.serve(requests)));
+	let started = Instant::now();
+
+	pledge("stdio inet unix sendfd recvfd", None).expect("pledge");
+
+	let notify = |state: &str| {
+		if let Some(Err(err)) = notify.as_ref().map(|notify| notify.send(state)) {
+			log::warning!("notify: {err}");
+		}
+	};
+	notify(&format!("READY=1\nMAINPID={}", std::process::id()));
+
+	let mut sighup = signal(SignalKind::hangup()).expect("signal");
+	let mut sigusr1 = signal(SignalKind::user_defined1()).expect("signal");
 	let mut sigint = signal(SignalKind::interrupt()).expect("signal");
 	let mut sigterm = signal(SignalKind::terminate()).expect("signal");
 
-	tokio::select! {
-		_ = sigchld.recv() => {
-			eprintln!("received SIGCHLD, exiting...");
-		},
-		_ = sigint.recv() => {},
-		_ = sigterm.recv() => {},
+	loop {
+		tokio::select! {
+			/* Only accepting races the other branches, a connection
+			 * is handed off in full before anything else is looked at
+			 */
+			accepted = server.accept() => {
+				let res = match accepted {
+					Ok(accepted) => server.serve(accepted).await,
+					Err(err) => Err(err),
+				};
+				if let Err(err) = res {
+					log::error!("{err}");
+				}
+			}
+			_ = sighup.recv() => {
+				let _ = reload(options, &mut server, &control).await;
+			}
+			_ = sigusr1.recv() => {
+				let _ = reopen(&server).await;
+			}
+			Some((request, socket)) = commands.recv() => {
+				if command(options, &mut server, &control, started, request, socket).await {
+					break;
+				}
+			}
+			/* Retired children exit on their own, current ones that
+			 * exit are restarted
+			 */
+			_ = exits.changed() => {
+				if helper.gone() {
+					log::crit!("helper process exited");
+					return ExitCode::FAILURE;
+				}
+				if !server.reap() {
+					break;
+				}
+			}
+			_ = server.drain_due() => server.drain().await,
+			_ = server.restart_due() => {
+				if !server.restart().await {
+					break;
+				}
+			}
+			_ = sigint.recv() => break,
+			_ = sigterm.recv() => break,
+		}
+	}
+	notify("STOPPING=1");
+	if let Some(acceptor) = acceptor {
+		acceptor.abort();
+	}
+	if let Err(err) = server.end().await {
+		log::error!("{err}");
+	}
+	if let Err(err) = helper.exit().await {
+		log::error!("helper: {err}");
+	}
+	ExitCode::SUCCESS
+}
+
+/* On SIGHUP and when asked on the control socket, errors are logged
+ * and passed on for the latter
+ */
+async fn reload(options: &Options, server: &mut Manager, control: &Option<String>)
+-> Result<(), String> {
+	let config = match server.helper.config().await {
+		Ok(text) => Config::parse(&text).map_err(|err| format!("{}:{err}", options.config)),
+		Err(err) => Err(err.to_string()),
+	};
+	let result = match config {
+		Ok(config) => {
+			if config.control != *control {
+				log::warning!("control socket is not changed until restarted");
+			}
+			server.reload(config).await.map_err(|err| err.to_string())
+		}
+		Err(err) => Err(err),
+	};
+	match &result {
+		Ok(()) => {
+			log::notice!("reloaded configuration from {}", options.config);
+			print_listeners(server.config());
+		}
+		Err(err) => log::error!("reload failed: {err}"),
+	}
+	result
+}
+
+async fn reopen(server: &Manager) -> Result<(), String> {
+	let result = match log::file() {
+		Some(path) => server.helper.open(path).await.map(log::reopen),
+		None => Ok(()),
+	};
+	let result = match result {
+		Ok(()) => server.reopen().await,
+		Err(err) => Err(err),
+	};
+	match &result {
+		Ok(()) => log::info!("reopened log files"),
+		Err(err) => log::error!("reopen failed: {err}"),
+	}
+	result.map_err(|err| err.to_string())
+}
+
+/* Answers a request on the control socket, returns true when the
+ * server is to stop. The clients' answers to status and connections
+ * are waited for in a task of their own
+ */
+async fn command(options: &Options, server: &mut Manager, control: &Option<String>,
+started: Instant, request: control::Request, socket: UnixSeqpacket) -> bool {
+	log::debug!("control socket: {request:?}");
+	let answer = |result: Result<(), String>| result.map_or_else(control::Response::Failed,
+		|()| control::Response::Done);
+	let response = match request {
+		control::Request::Status => {
+			let pid = std::process::id();
+			let uptime = started.elapsed();
+			let children = server.status().await;
+			tokio::spawn(async move {
+				let children = children.await.unwrap_or_default();
+				let status = control::Status { pid, uptime, children };
+				control::reply(&socket, &[control::Response::Status(status)]).await;
+			});
+			return false;
+		}
+		control::Request::Connections => {
+			let connections = server.connections().await;
+			tokio::spawn(async move {
+				let mut responses: Vec<_> = connections.await.unwrap_or_default().into_iter()
+					.map(control::Response::Connection)
+					.collect();
+				responses.push(control::Response::Done);
+				control::reply(&socket, &responses).await;
+			});
+			return false;
+		}
+		control::Request::Reload => answer(reload(options, server, control).await),
+		control::Request::Reopen => answer(reopen(server).await),
+		control::Request::Stop => {
+			log::notice!("stopping as asked on the control socket");
+			control::Response::Done
+		}
+	};
+	control::reply(&socket, &[response]).await;
+	request == control::Request::Stop
+}
+
+fn print_listeners(config: &Config) {
+	for server in &config.servers {
+		for listen in &server.listen {
+			log::info!("server \"{}\": listening on {}", server.name, listen.addr);
+		}
+	}
+}
+
+fn usage() -> ! {
+	eprintln!("usage: httpd [-dnUv] [-f file]");
+	eprintln!("       httpd [-f file] -p ctl command");
+	std::process::exit(1);
+}
+
+/* -p and -u are private, they are used when the parent re-executes
+ * itself to start one of the child processes. -p ctl is the exception,
+ * it takes the command for the control socket as its arguments
+ */
+#[derive(Debug, PartialEq)]
+struct Options {
+	config: String,
+	check: bool,
+	debug: bool,
+	verbose: u8,
+	unprivileged: bool,
+	process: Option<String>,
+	user: Option<String>,
+	group: Option<String>,
+	chroot: Option<String>,
+	seccomp: Option<String>,
+	command: Vec<String>,
+}
+
+impl Options {
+	fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+		let mut options = Self {
+			config: config::CONFIG_FILE.to_string(),
+			check: false,
+			debug: false,
+			verbose: 0,
+			unprivileged: false,
+			process: None,
+			user: None,
+			group: None,
+			chroot: None,
+			seccomp: None,
+			command: Vec::new(),
+		};
+		let mut args = args.into_iter();
+		while let Some(arg) = args.next() {
+			if arg == "--" {
+				break;
+			}
+			let Some(flags) = arg.strip_prefix('-').filter(|flags| !flags.is_empty()) else {
+				if options.process.as_deref() == Some("ctl") {
+					options.command = std::iter::once(arg).chain(args).collect();
+					return Ok(options);
+				}
+				return Err(format!("unexpected argument -- {arg}"));
+			};
+			for (idx, flag) in flags.char_indices() {
+				match flag {
+					'd' => options.debug = true,
+					'n' => options.check = true,
+					'U' => options.unprivileged = true,
+					'v' => options.verbose = options.verbose.saturating_add(1),
+					'c' | 'f' | 'g' | 'p' | 's' | 'u' => {
+						/* -ffile and -f file are both accepted */
+						let rest = &flags[idx + flag.len_utf8()..];
+						let value = if rest.is_empty() {
+							args.next()
+								.ok_or(format!("option requires an argument -- {flag}"))?
+						}
+						else {
+							rest.to_string()
+						};
+						match flag {
+							'c' => options.chroot = Some(value),
+							'f' => options.config = value,
+							'g' => options.group = Some(value),
+							'p' => options.process = Some(value),
+							's' => options.seccomp = Some(value),
+							_ => options.user = Some(value),
+						}
+						break;
+					}
+					_ => return Err(format!("unknown option -- {flag}")),
+				}
+			}
+		}
+		if let Some(arg) = args.next() {
+			return Err(format!("unexpected argument -- {arg}"));
+		}
+		Ok(options)
 	}
-	token.cancel();
 }
 
 struct GlobalConfig {
-	mime: tokio::fs::File,
+	mime: std::fs::File,
 }
 
 impl GlobalConfig {
-	async fn new(path: &str) -> std::io::Result<Self> {
-		let mime = tokio::fs::File::open(path).await?;
+	async fn new(helper: &helper::Helper, path: &str) -> std::io::Result<Self> {
+		let mime = helper.open(path).await?;
 		Ok(Self {
 			mime
 		})
@@ -113,99 +610,888 @@ impl GlobalConfig {
 }
 
 struct TlsConfig<'a> {
+	names: Vec<&'a str>,
 	cert: &'a str,
 	key: &'a str,
 }
 
+/* tls has an entry for each server listening with TLS, the crypto
+ * process is only started if it is not empty, the same goes for logs
+ * and the logger process
+ */
 struct ManagerConfig<'a> {
-	tls: Option<TlsConfig<'a>>,
-	fs: fs::Server,
-	addr: std::net::SocketAddr,
+	tls: Vec<TlsConfig<'a>>,
+	crypto: CryptoConfig,
+	fs: Vec<fs::Server>,
+	client: ClientConfig,
+	logs: Vec<&'a str>,
+	logger: LoggerConfig,
 }
 
 enum Acceptor {
-	Tls(proc::Process),
+	Tls,
 	Plain,
 }
 
-struct Manager {
-	fs: proc::Process, 
-	client: proc::Process,
+impl From<&config::Listen> for Acceptor {
+	fn from(listen: &config::Listen) -> Self {
+		if listen.tls { Acceptor::Tls } else { Acceptor::Plain }
+	}
+}
+
+enum Socket {
+	Tcp(TcpListener),
+	Unix(UnixListener),
+}
+
+struct Listener {
+	addr: Address,
+	socket: Socket,
 	acceptor: Acceptor,
+	proxy: Option<Vec<network::Network>>,
+}
+
+impl Listener {
+	/* Bound by the helper */
+	async fn bind(helper: &helper::Helper, listen: &config::Listen) -> std::io::Result<Self> {
+		Self::adopt(listen, helper.bind(&listen.addr).await?)
+	}
+
+	/* Takes over a socket bound for the listener, by the helper or
+	 * the service manager
+	 */
+	fn adopt(listen: &config::Listen, fd: OwnedFd) -> std::io::Result<Self> {
+		let socket = match listen.addr {
+			Address::Inet(_) => {
+				let socket = std::net::TcpListener::from(fd);
+				socket.set_nonblocking(true)?;
+				Socket::Tcp(TcpListener::from_std(socket)?)
+			}
+			Address::Unix(_) => {
+				let socket = std::os::unix::net::UnixListener::from(fd);
+				socket.set_nonblocking(true)?;
+				Socket::Unix(UnixListener::from_std(socket)?)
+			}
+		};
+		Ok(Self {
+			addr: listen.addr.clone(), socket, acceptor: listen.into(), proxy: listen.proxy.clone(),
+		})
+	}
+
+	fn poll_accept(&self, cx: &mut std::task::Context<'_>)
+	-> Poll<std::io::Result<(OwnedFd, client::Remote)>> {
+		match &self.socket {
+			Socket::Tcp(socket) => socket.poll_accept(cx).map(|res| {
+				let (con, remote) = res?;
+				Ok((OwnedFd::from(con.into_std()?), client::Remote::Inet(remote)))
+			}),
+			Socket::Unix(socket) => socket.poll_accept(cx).map(|res| {
+				let (con, _) = res?;
+				Ok((OwnedFd::from(con.into_std()?), client::Remote::Unix))
+			}),
+		}
+	}
 
-	listener: TcpListener,
+	/* Whether a connection starts with a PROXY protocol header,
+	 * peers on unix sockets are as trusted as the socket's mode makes
+	 * them
+	 */
+	fn trusts(&self, remote: client::Remote) -> bool {
+		self.proxy.as_ref().is_some_and(|trusted| remote.trusted(trusted))
+	}
+}
+
+/* Client processes are told apart by their index in the pool */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Role {
+	Filesystem,
+	Client(usize),
+	Crypto,
+	Logger,
+}
+
+impl Role {
+	fn name(self) -> &'static str {
+		match self {
+			Role::Filesystem => "filesystem",
+			Role::Client(_) => "client",
+			Role::Crypto => "crypto",
+			Role::Logger => "logger",
+		}
+	}
+}
+
+impl std::fmt::Display for Role {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Role::Client(idx) => write!(f, "client {idx}"),
+			_ => f.write_str(self.name()),
+		}
+	}
+}
+
+/* A process that keeps exiting is restarted after a delay that doubles
+ * each time, until it has been restarted RESTART_LIMIT times without
+ * staying up for RESTART_WINDOW in between
+ */
+#[derive(Default)]
+struct Restarts {
+	count: u32,
+	last: Option<Instant>,
+}
+
+impl Restarts {
+	const LIMIT: u32 = 5;
+	const WINDOW: Duration = Duration::from_secs(60);
+	const DELAY: Duration = Duration::from_millis(100);
+
+	/* Returns how long to wait before restarting, None to give up */
+	fn delay(&mut self, now: Instant) -> Option<Duration> {
+		if self.last.is_some_and(|last| now.duration_since(last) > Self::WINDOW) {
+			self.count = 0;
+		}
+		self.last = Some(now);
+		self.count += 1;
+		if self.count > Self::LIMIT {
+			return None;
+		}
+		Some(Self::DELAY * 2u32.pow(self.count - 1))
+	}
+}
+
+/* The processes serving connections for one configuration,
+ * they are replaced as a whole on reload
+ */
+struct Children {
+	config: Config,
+	fs: proc::Process, 
+	clients: Vec<proc::Process>,
+	crypto: Option<proc::Process>,
+	logger: Option<proc::Process>,
+	/* The shared metrics, for the clients and crypto process */
+	metrics: OwnedFd,
+	/* Where each client counts its connections */
+	slots: Vec<usize>,
+}
+
+struct Manager {
+	helper: Arc<helper::Helper>,
+	children: Children,
+	/* Draining after a reload, until their drain timeout is up and
+	 * they are killed
+	 */
+	retired: Vec<(Children, Option<Instant>)>,
+	listeners: Vec<Listener>,
+	restarts: HashMap<Role, Restarts>,
+	pending: Vec<(Role, Instant)>,
+	next: Cell<usize>,
+	/* The listener polled first, so that a busy one cannot starve
+	 * those after it
+	 */
+	next_listener: Cell<usize>,
+	/* Kept across reloads, the parent counts accepted connections */
+	metrics: metrics::Metrics,
+	/* The slots no client of any generation counts in */
+	free: Vec<usize>,
 }
 
 /* XXX: do priviledged things (opening socket, exec-ing) 
  * before doing serde things
  */
-impl Manager {
-	async fn new(prog: &str, config: ManagerConfig<'_>, global_config: GlobalConfig) 
+impl Children {
+	async fn new(helper: &Arc<helper::Helper>, config: Config, metrics: OwnedFd, slots: Vec<usize>)
 	-> std::io::Result<Self> {
+		let manager_config = config.manager_config();
+		let fs = Self::spawn_fs(helper, &config, &manager_config).await?;
+		let logger = Self::spawn_logger(helper, &config, &manager_config).await?;
+		let mut clients = Vec::new();
+		for (idx, slot) in slots.iter().enumerate() {
+			let client = Self::spawn_client(helper, &config, &manager_config, &metrics, *slot,
+				config.client_connections(idx)).await?;
+			Self::connect(&fs, &client, client::Message::Filesystem).await?;
+			if let Some(logger) = &logger {
+				Self::connect(logger, &client, client::Message::Logger).await?;
+			}
+			clients.push(client);
+		}
+		let crypto = Self::spawn_crypto(helper, &config, &manager_config, &metrics).await?;
+		drop(manager_config);
+		Ok(Self {
+			config, fs, clients, crypto, logger, metrics, slots
+		})
+	}
 
-		let fs = proc::ProcessBuilder::new(prog, "filesystem")
-			.build()?;
-		let client = proc::ProcessBuilder::new(prog, "client")
-			.build()?;
-		let acceptor = match config.tls {
-			Some(ref tls) => {
-				let certfile = tokio::fs::File::open(tls.cert).await?.into_std().await;
-				let keyfile = tokio::fs::File::open(tls.key).await?.into_std().await;
-				let crypto = proc::ProcessBuilder::new(prog, "crypto")
-					.build()?;
+	/* Only the filesystem process is chrooted to the documents */
+	async fn spawn(helper: &Arc<helper::Helper>, config: &Config, role: Role)
+	-> std::io::Result<proc::Process> {
+		let chroot = match role {
+			Role::Filesystem => &config.chroot,
+			_ => &config.empty,
+		};
+		helper.spawn(helper::Spawn {
+			role: role.name().to_string(),
+			user: config.user.clone(),
+			group: config.group.clone(),
+			chroot: chroot.clone(),
+			seccomp: config.seccomp,
+		}).await
+	}
 
-				crypto.peer().send_fds(&[certfile.into(), keyfile.into()]).await?;
-				Acceptor::Tls(crypto)
-			}
-			None => {
-				Acceptor::Plain
+	async fn spawn_fs(helper: &Arc<helper::Helper>, config: &Config,
+	manager_config: &ManagerConfig<'_>) -> std::io::Result<proc::Process> {
+		let fs = Self::spawn(helper, config, Role::Filesystem).await?;
+		let buf = serde_cbor::to_vec(&manager_config.fs).expect("serde");
+		fs.peer().socket().send(&buf).await?;
+		Ok(fs)
+	}
+
+	async fn spawn_client(helper: &Arc<helper::Helper>, config: &Config,
+	manager_config: &ManagerConfig<'_>, metrics: &OwnedFd, slot: usize, max_connections: usize)
+	-> std::io::Result<proc::Process> {
+		let global_config = GlobalConfig::new(helper, &config.types).await?;
+		let client = Self::spawn(helper, config, Role::Client(0)).await?;
+		let buf = serde_cbor::to_vec(&(&manager_config.client, slot, max_connections))
+			.expect("serde");
+		client.peer().send_fds(&[global_config.mime.into(), metrics.try_clone()?], &buf).await?;
+		Ok(client)
+	}
+
+	async fn spawn_crypto(helper: &Arc<helper::Helper>, config: &Config,
+	manager_config: &ManagerConfig<'_>, metrics: &OwnedFd)
+	-> std::io::Result<Option<proc::Process>> {
+		if manager_config.tls.is_empty() {
+			return Ok(None);
+		}
+		/* Checked here as well, so that a reload is refused over a
+		 * certificate or key that cannot be used
+		 */
+		let mut files = Vec::new();
+		for tls in &manager_config.tls {
+			let mut certfile = helper.open(tls.cert).await?;
+			let mut keyfile = helper.open(tls.key).await?;
+			if let Err(err) = tls::certified_key(&certfile, &keyfile) {
+				return Err(std::io::Error::new(err.kind(),
+					format!("server \"{}\": {}, {}: {err}", tls.names[0], tls.cert, tls.key)));
 			}
-		};
+			certfile.rewind()?;
+			keyfile.rewind()?;
+			files.push((certfile, keyfile));
+		}
+		let crypto = Self::spawn(helper, config, Role::Crypto).await?;
 
+		let buf = serde_cbor::to_vec(&manager_config.crypto).expect("serde");
+		crypto.peer().send_with_fd(metrics.try_clone()?, &buf).await?;
+		for (certfile, keyfile) in files {
+			crypto.peer().send_fds(&[certfile.into(), keyfile.into()], &[]).await?;
+		}
+		/* Not counted as up before it says so */
+		let mut buf = [0u8; 1024];
+		let recv = crypto.peer().socket().recv(&mut buf);
+		let len = tokio::time::timeout(crypto::READY_TIMEOUT, recv).await??;
+		if len == 0 {
+			return Err(std::io::Error::other("crypto process exited"));
+		}
+		let ready: crypto::Ready = serde_cbor::from_slice(&buf[..len])
+			.map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidData))?;
+		ready.map_err(|err| std::io::Error::other(format!("crypto process: {err}")))?;
+		Ok(Some(crypto))
+	}
+
+	async fn open_logs(helper: &helper::Helper, manager_config: &ManagerConfig<'_>)
+	-> std::io::Result<Vec<std::fs::File>> {
+		let mut files = Vec::new();
+		for log in &manager_config.logs {
+			files.push(helper.open(log).await?);
+		}
+		Ok(files)
+	}
+
+	/* The log files are opened by the helper, the logger process
+	 * cannot
+	 */
+	async fn spawn_logger(helper: &Arc<helper::Helper>, config: &Config,
+	manager_config: &ManagerConfig<'_>) -> std::io::Result<Option<proc::Process>> {
+		if manager_config.logs.is_empty() {
+			return Ok(None);
+		}
+		let files = Self::open_logs(helper, manager_config).await?;
+		let logger = Self::spawn(helper, config, Role::Logger).await?;
+		let buf = serde_cbor::to_vec(&manager_config.logger).expect("serde");
+		logger.peer().socket().send(&buf).await?;
+		for file in files {
+			logger.peer().send_fds(&[file.into()], &[]).await?;
+		}
+		Ok(Some(logger))
+	}
+
+	/* Gives the client a channel of its own to the filesystem or
+	 * logger process
+	 */
+	async fn connect(process: &proc::Process, client: &proc::Process, message: client::Message)
+	-> std::io::Result<()> {
 		let (a, b) = UnixSeqpacket::pair()?;
+		/* The logger is also sent reopened files, so it is told
+		 * what the descriptor is for
+		 */
+		let buf = match message {
+			client::Message::Logger => serde_cbor::to_vec(&logger::Message::Client).expect("serde"),
+			_ => Vec::new(),
+		};
+		process.peer().send_with_fd(a, &buf).await?;
+		let buf = serde_cbor::to_vec(&message).expect("serde");
+		client.peer().send_with_fd(b, &buf).await?;
+		Ok(())
+	}
 
-		let buf = serde_cbor::to_vec(&config.fs).expect("serde");
-		fs.peer().send_with_fd(a, &buf).await?;
-		let mime = global_config.mime.into_std().await;
-		let mime = OwnedFd::from(mime);
-		client.peer().send_fds(&[b.into(), mime]).await?;
+	/* Replaces a process that exited, the others are told about
+	 * the new one where they need to talk to it
+	 */
+	async fn respawn(&mut self, helper: &Arc<helper::Helper>, role: Role) -> std::io::Result<()> {
+		let manager_config = self.config.manager_config();
+		match role {
+			Role::Filesystem => {
+				let fs = Self::spawn_fs(helper, &self.config, &manager_config).await?;
+				for client in &self.clients {
+					Self::connect(&fs, client, client::Message::Filesystem).await?;
+				}
+				self.fs = fs;
+			}
+			Role::Client(idx) => {
+				let client = Self::spawn_client(helper, &self.config, &manager_config,
+					&self.metrics, self.slots[idx], self.config.client_connections(idx)).await?;
+				Self::connect(&self.fs, &client, client::Message::Filesystem).await?;
+				if let Some(logger) = &self.logger {
+					Self::connect(logger, &client, client::Message::Logger).await?;
+				}
+				self.clients[idx] = client;
+			}
+			Role::Logger => {
+				let logger = Self::spawn_logger(helper, &self.config, &manager_config).await?;
+				if let Some(logger) = &logger {
+					for client in &self.clients {
+						Self::connect(logger, client, client::Message::Logger).await?;
+					}
+				}
+				self.logger = logger;
+			}
+			Role::Crypto => {
+				self.crypto = Self::spawn_crypto(helper, &self.config, &manager_config,
+					&self.metrics).await?;
+			}
+		}
+		Ok(())
+	}
 
-		let client_config = ClientConfig {
-			tls: config.tls.is_some(),
+	/* Every log file is opened again, so that logs that were moved
+	 * away are started anew, the logger closes the old ones
+	 */
+	async fn reopen(&self, helper: &helper::Helper) -> std::io::Result<()> {
+		let Some(logger) = &self.logger else {
+			return Ok(());
 		};
-		let buf = serde_cbor::to_vec(&client_config).expect("serde");
-		client.peer().socket().send(&buf).await?;
+		let manager_config = self.config.manager_config();
+		let files = Self::open_logs(helper, &manager_config).await?;
+		for (idx, file) in files.into_iter().enumerate() {
+			let buf = serde_cbor::to_vec(&logger::Message::Reopen(idx)).expect("serde");
+			logger.peer().send_with_fd(file, &buf).await?;
+		}
+		Ok(())
+	}
+
+	fn roles(&self) -> Vec<(Role, &proc::Process)> {
+		let mut processes = vec![(Role::Filesystem, &self.fs)];
+		processes.extend(self.clients.iter().enumerate()
+			.map(|(idx, client)| (Role::Client(idx), client)));
+		processes.extend(self.crypto.as_ref().map(|crypto| (Role::Crypto, crypto)));
+		processes.extend(self.logger.as_ref().map(|logger| (Role::Logger, logger)));
+		processes
+	}
+
+	fn exited(&self) -> Vec<(Role, std::process::ExitStatus)> {
+		self.roles().into_iter()
+			.filter_map(|(role, process)| Some((role, process.try_wait()?)))
+			.collect()
+	}
+
+	fn all_exited(&self) -> bool {
+		self.roles().into_iter().all(|(_, process)| process.try_wait().is_some())
+	}
+
+	/* The children finish the connections they already have and
+	 * exit once their channel to the parent is closed
+	 */
+	fn retire(&self) {
+		for (_, process) in self.roles() {
+			if let Err(err) = process.close() {
+				log::warning!("{err}");
+			}
+		}
+	}
+
+	/* The client and crypto processes stop taking connections and
+	 * answer the requests in progress, the filesystem process exits
+	 * once the clients are done with it
+	 */
+	async fn terminate(&self) {
+		for client in &self.clients {
+			client.terminate().await;
+		}
+		if let Some(crypto) = &self.crypto {
+			crypto.terminate().await;
+		}
+		for process in [Some(&self.fs), self.logger.as_ref()].into_iter().flatten() {
+			if let Err(err) = process.close() {
+				log::warning!("{err}");
+			}
+		}
+	}
+
+	async fn kill(&self) {
+		for (_, process) in self.roles() {
+			process.kill().await;
+		}
+	}
 
-		let listener = TcpListener::bind(config.addr).await?;
+	async fn end(self, deadline: Instant) -> std::io::Result<()> {
+		if let Some(crypto) = self.crypto {
+			crypto.end(deadline).await?;
+		}
+		for client in self.clients {
+			client.end(deadline).await?;
+		}
+		self.fs.end(deadline).await?;
+		if let Some(logger) = self.logger {
+			logger.end(deadline).await?;
+		}
+		Ok(())
+	}
+}
+
+impl Manager {
+	async fn new(helper: Arc<helper::Helper>, config: Config,
+	mut adopted: HashMap<Address, OwnedFd>) -> std::io::Result<Self> {
+		let mut listeners = Vec::new();
+		for listen in config.listeners() {
+			listeners.push(match adopted.remove(&listen.addr) {
+				Some(fd) => Listener::adopt(&listen, fd)?,
+				None => Listener::bind(&helper, &listen).await?,
+			});
+		}
+		/* Enough for the clients and those of one reload draining */
+		let (metrics, fd) = metrics::Metrics::create(config.prefork * 2)?;
+		let mut free: Vec<usize> = (0..metrics.slots()).rev().collect();
+		let slots = Self::slots(&mut free, metrics, &fd, config.prefork)?;
+		let children = Children::new(&helper, config, fd, slots).await?;
 		Ok(Self {
-			fs, client, acceptor, listener
+			children, listeners, metrics, free, helper,
+			retired: Vec::new(),
+			restarts: HashMap::new(),
+			pending: Vec::new(),
+			next: Cell::new(0),
+			next_listener: Cell::new(0),
 		})
 	}
 
-	async fn serve(&self) -> std::io::Result<()> {
-		let (con, _) = self.listener.accept().await?;
-		match &self.acceptor {
+	/* Adds slots when too few are free, more generations drain than
+	 * there was room for
+	 */
+	fn slots(free: &mut Vec<usize>, metrics: metrics::Metrics, fd: &OwnedFd, count: usize)
+	-> std::io::Result<Vec<usize>> {
+		if let Some(more) = count.checked_sub(free.len()).filter(|more| *more > 0) {
+			let slots = metrics.slots();
+			metrics.grow(fd, slots + more)?;
+			free.extend((slots..slots + more).rev());
+		}
+		Ok(free.split_off(free.len() - count))
+	}
+
+	/* The clients that counted in these are gone, they do not serve
+	 * anything anymore
+	 */
+	fn release(free: &mut Vec<usize>, metrics: metrics::Metrics, slots: &[usize]) {
+		for slot in slots {
+			metrics.reset(*slot);
+			free.push(*slot);
+		}
+	}
+
+	fn config(&self) -> &Config {
+		&self.children.config
+	}
+
+	/* Listening sockets are kept across reloads when their address
+	 * is still in use, new children take over new connections while
+	 * the old ones drain in the background
+	 */
+	async fn reload(&mut self, config: Config) -> std::io::Result<()> {
+		let listen = config.listeners();
+		let mut bound = Vec::new();
+		for listen in &listen {
+			if !self.listeners.iter().any(|listener| listener.addr == listen.addr) {
+				bound.push(Listener::bind(&self.helper, listen).await?);
+			}
+		}
+		let slots = Self::slots(&mut self.free, self.metrics, &self.children.metrics,
+			config.prefork)?;
+		let children = match Children::new(&self.helper, config,
+		self.children.metrics.try_clone()?, slots.clone()).await {
+			Ok(children) => children,
+			Err(err) => {
+				Self::release(&mut self.free, self.metrics, &slots);
+				return Err(err);
+			}
+		};
+
+		let mut old = std::mem::take(&mut self.listeners);
+		for listen in &listen {
+			let mut listener = match old.iter().position(|listener| listener.addr == listen.addr) {
+				Some(idx) => old.swap_remove(idx),
+				None => {
+					let idx = bound.iter().position(|listener| listener.addr == listen.addr)
+						.expect("listener");
+					bound.swap_remove(idx)
+				}
+			};
+			listener.acceptor = listen.into();
+			listener.proxy = listen.proxy.clone();
+			self.listeners.push(listener);
+		}
+		/* Those no longer listened on are closed, the helper removes
+		 * the unix sockets it bound
+		 */
+		for listener in old {
+			if let Err(err) = self.helper.unlink(&listener.addr).await {
+				log::warning!("{}: {err}", listener.addr);
+			}
+		}
+
+		let old = std::mem::replace(&mut self.children, children);
+		old.retire();
+		let deadline = Instant::now() + old.config.drain_timeout;
+		self.retired.push((old, Some(deadline)));
+		self.pending.clear();
+		Ok(())
+	}
+
+	/* Forgets about retired children that are done draining and
+	 * schedules a restart for each current one that exited, returns
+	 * false when one of them keeps exiting
+	 */
+	fn reap(&mut self) -> bool {
+		let (free, metrics) = (&mut self.free, self.metrics);
+		self.retired.retain(|(children, _)| {
+			let done = children.all_exited();
+			if done {
+				Self::release(free, metrics, &children.slots);
+			}
+			!done
+		});
+		let now = Instant::now();
+		for (role, status) in self.children.exited() {
+			if let Role::Client(idx) = role {
+				self.metrics.reset(self.children.slots[idx]);
+			}
+			if self.pending.iter().any(|(pending, _)| *pending == role) {
+				continue;
+			}
+			log::warning!("{role} process exited: {status}");
+			if !self.schedule(role, now) {
+				return false;
+			}
+		}
+		true
+	}
+
+	fn schedule(&mut self, role: Role, now: Instant) -> bool {
+		match self.restarts.entry(role).or_default().delay(now) {
+			Some(delay) => {
+				self.pending.push((role, now + delay));
+				true
+			}
+			None => {
+				log::crit!("{role} process keeps exiting, giving up");
+				false
+			}
+		}
+	}
+
+	async fn reopen(&self) -> std::io::Result<()> {
+		self.children.reopen(&self.helper).await
+	}
+
+	/* Completes once a retired generation is out of time */
+	async fn drain_due(&self) {
+		match self.retired.iter().filter_map(|(_, deadline)| *deadline).min() {
+			Some(at) => tokio::time::sleep_until(at).await,
+			None => std::future::pending().await,
+		}
+	}
+
+	/* Kills what is left of the retired generations that are out of
+	 * time, they are forgotten once they exited
+	 */
+	async fn drain(&mut self) {
+		let now = Instant::now();
+		for (children, deadline) in &mut self.retired {
+			if deadline.is_some_and(|at| at <= now) {
+				*deadline = None;
+				log::warning!("killing retired processes still draining after {}s",
+					children.config.drain_timeout.as_secs());
+				children.kill().await;
+			}
+		}
+	}
+
+	/* Completes once a scheduled restart is due */
+	async fn restart_due(&self) {
+		match self.pending.iter().map(|(_, at)| *at).min() {
+			Some(at) => tokio::time::sleep_until(at).await,
+			None => std::future::pending().await,
+		}
+	}
+
+	/* Restarts the processes that are due, a restart that fails is
+	 * counted like the process exiting again
+	 */
+	async fn restart(&mut self) -> bool {
+		let now = Instant::now();
+		let due: Vec<Role> = self.pending.iter()
+			.filter(|(_, at)| *at <= now)
+			.map(|(role, _)| *role)
+			.collect();
+		self.pending.retain(|(_, at)| *at > now);
+		for role in due {
+			match self.children.respawn(&self.helper, role).await {
+				Ok(()) => log::notice!("restarted {role} process"),
+				Err(err) => {
+					log::error!("restarting {role} process failed: {err}");
+					if !self.schedule(role, now) {
+						return false;
+					}
+				}
+			}
+		}
+		true
+	}
+
+	async fn accept(&self) -> std::io::Result<(OwnedFd, client::Remote, usize)> {
+		std::future::poll_fn(|cx| {
+			let start = self.next_listener.get();
+			for offset in 0..self.listeners.len() {
+				let idx = (start + offset) % self.listeners.len();
+				if let Poll::Ready(res) = self.listeners[idx].poll_accept(cx) {
+					self.next_listener.set(idx + 1);
+					return Poll::Ready(res.map(|(con, remote)| (con, remote, idx)));
+				}
+			}
+			Poll::Pending
+		}).await
+	}
+
+	/* Connections are handed to the client processes in turn,
+	 * skipping those that exited and wait to be restarted and those
+	 * at their limit. When all are, one queues it until it has room
+	 */
+	fn next_client(&self) -> &proc::Process {
+		let children = &self.children;
+		let clients = &children.clients;
+		let start = self.next.get();
+		let usable = |idx: &usize| !self.pending.iter().any(|(role, _)| *role == Role::Client(*idx));
+		let room = |idx: &usize| {
+			let active = self.metrics.active_in(children.slots[*idx]);
+			let limit = children.config.client_connections(*idx);
+			active < limit.try_into().unwrap_or(u64::MAX)
+		};
+		let mut order = (0..clients.len()).map(|offset| (start + offset) % clients.len());
+		let idx = order.clone().find(|idx| usable(idx) && room(idx))
+			.or_else(|| order.find(usable))
+			.unwrap_or(start % clients.len());
+		self.next.set(idx + 1);
+		&clients[idx]
+	}
+
+	/* Not cancel-safe, it is awaited in full once accept returned */
+	async fn serve(&self, (con, remote, idx): (OwnedFd, client::Remote, usize))
+	-> std::io::Result<()> {
+		self.metrics.accepted.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+		let listener = &self.listeners[idx];
+		let proxy = listener.trusts(remote);
+		let client = self.next_client();
+		match listener.acceptor {
 			Acceptor::Plain => {
-				let con = OwnedFd::from(con.into_std()?);
-				self.client.peer().send_with_fd(con, &[]).await?;
+				let message = client::Message::Accept { listener: idx, remote, proxy };
+				let buf = serde_cbor::to_vec(&message).expect("serde");
+				client.peer().send_with_fd(con, &buf).await?;
 			}
-			Acceptor::Tls(tls) => {
+			/* The crypto process reads the header ahead of the TLS
+			 * handshake and passes on what it said
+			 */
+			Acceptor::Tls => {
+				let crypto = self.children.crypto.as_ref().expect("no crypto process");
 				let (a, b) = UnixStream::pair()?;
 				let a = a.into_std()?;
-				let con = OwnedFd::from(con.into_std()?);
-				tls.peer().send_fds(&[con, a.into()]).await?;
-				self.client.peer().send_with_fd(b.into_std()?, &[]).await?;
+				let accept = crypto::Accept { listener: idx, proxy };
+				let accept = serde_cbor::to_vec(&accept).expect("serde");
+				crypto.peer().send_fds(&[con, a.into()], &accept).await?;
+				let message = client::Message::Accept { listener: idx, remote, proxy: false };
+				let buf = serde_cbor::to_vec(&message).expect("serde");
+				client.peer().send_with_fd(b.into_std()?, &buf).await?;
 			}
 		}
 		Ok(())
 	}
 
+	/* Every child, the clients with how many connections they
+	 * serve. Retired clients are no longer asked, their channel is
+	 * closed. The answers are collected in the task returned
+	 */
+	async fn status(&self) -> JoinHandle<Vec<control::Child>> {
+		let current = std::iter::once((false, &self.children));
+		let retired = self.retired.iter().map(|(children, _)| (true, children));
+		let mut status = Vec::new();
+		for (retired, children) in current.chain(retired) {
+			for (role, process) in children.roles() {
+				let query = match role {
+					Role::Client(_) if !retired => Self::query(process).await,
+					_ => None,
+				};
+				let child = control::Child {
+					role: role.to_string(), pid: process.id(), retired, connections: None,
+				};
+				status.push((child, query));
+			}
+		}
+		tokio::spawn(async move {
+			let mut children = Vec::new();
+			for (mut child, query) in status {
+				if let Some(query) = query {
+					child.connections = query.await.ok().flatten().map(|list| list.len());
+				}
+				children.push(child);
+			}
+			children
+		})
+	}
+
+	async fn connections(&self) -> JoinHandle<Vec<control::Connection>> {
+		let mut queries = Vec::new();
+		for client in &self.children.clients {
+			queries.push((client.id(), Self::query(client).await));
+		}
+		let listeners: Vec<_> = self.listeners.iter()
+			.map(|listener| listener.addr.to_string())
+			.collect();
+		tokio::spawn(async move {
+			let mut connections = Vec::new();
+			for (pid, query) in queries {
+				let Some(query) = query else {
+					continue;
+				};
+				for connection in query.await.ok().flatten().unwrap_or_default() {
+					let listener = listeners.get(connection.listener)
+						.map_or("-".to_string(), String::clone);
+					connections.push(control::Connection {
+						pid,
+						listener,
+						remote: connection.remote,
+						since: connection.since,
+						requests: connection.requests,
+					});
+				}
+			}
+			connections
+		})
+	}
+
+	/* Asks a client for its connections, every client answers in a
+	 * task of its own that gives None when the answer does not come
+	 * in time, as when it waits for connections to finish with max
+	 * connections reached
+	 */
+	async fn query(client: &proc::Process) -> Option<JoinHandle<Option<Vec<client::Connection>>>> {
+		let (mine, theirs) = UnixSeqpacket::pair().ok()?;
+		let buf = serde_cbor::to_vec(&client::Message::Connections).expect("serde");
+		client.peer().send_with_fd(theirs, &buf).await.ok()?;
+		Some(tokio::spawn(async move {
+			tokio::time::timeout(control::TIMEOUT, async {
+				let mut connections = Vec::new();
+				let mut buf = vec![0u8; 1024];
+				loop {
+					match mine.recv(&mut buf).await.ok()? {
+						0 => return Some(connections),
+						len => connections.push(serde_cbor::from_slice(&buf[..len]).ok()?),
+					}
+				}
+			}).await.ok()?
+		}))
+	}
+
+	/* Stops accepting, then gives every child until the drain
+	 * timeout to finish its connections
+	 */
 	async fn end(self) -> std::io::Result<()> {
-		if let Acceptor::Tls(crypto) = self.acceptor {
-			crypto.end().await?;
+		drop(self.listeners);
+		let deadline = Instant::now() + self.children.config.drain_timeout;
+		let mut all: Vec<_> = self.retired.into_iter().map(|(children, _)| children).collect();
+		all.push(self.children);
+		for children in &all {
+			children.terminate().await;
+		}
+		for children in all {
+			children.end(deadline).await?;
 		}
-		self.fs.end().await?;
-		self.client.end().await?;
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	fn parse(args: &[&str]) -> Result<Options, String> {
+		Options::parse(args.iter().map(|arg| arg.to_string()))
+	}
+	#[test]
+	fn options() {
+		let options = parse(&["-dvv", "-f", "/tmp/httpd.conf", "-nU"]).unwrap();
+		assert_eq!(options, Options {
+			config: "/tmp/httpd.conf".to_string(),
+			check: true,
+			debug: true,
+			verbose: 2,
+			unprivileged: true,
+			process: None,
+			user: None,
+			group: None,
+			chroot: None,
+			seccomp: None,
+			command: Vec::new(),
+		});
+		let options = parse(&["-p", "client", "-uwww", "-c", "/var/empty", "-gwww"]).unwrap();
+		assert_eq!(options.process.as_deref(), Some("client"));
+		assert_eq!(options.user.as_deref(), Some("www"));
+		assert_eq!(options.group.as_deref(), Some("www"));
+		assert_eq!(options.chroot.as_deref(), Some("/var/empty"));
+		assert_eq!(options.config, config::CONFIG_FILE);
+
+		assert!(parse(&["-f"]).is_err());
+		assert!(parse(&["-x"]).is_err());
+		assert!(parse(&["httpd.conf"]).is_err());
+
+		let options = parse(&["-f", "/tmp/httpd.conf", "-p", "ctl", "status"]).unwrap();
+		assert_eq!(options.command, ["status"]);
+		assert!(parse(&["-p", "client", "status"]).is_err());
+	}
+
+	#[test]
+	fn restarts() {
+		let mut restarts = Restarts::default();
+		let now = Instant::now();
+		let delays: Vec<_> = (0..Restarts::LIMIT + 1).map(|_| restarts.delay(now)).collect();
+		assert_eq!(delays[0], Some(Restarts::DELAY));
+		assert_eq!(delays[1], Some(Restarts::DELAY * 2));
+		assert_eq!(delays[Restarts::LIMIT as usize], None);
+
+		let later = now + Restarts::WINDOW + Duration::from_secs(1);
+		assert_eq!(restarts.delay(later), Some(Restarts::DELAY));
+	}
+}
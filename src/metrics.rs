@@ -0,0 +1,292 @@
+/* Counters for the server as a whole, kept in memory the parent
+ * shares with every child. The processes count into it as they go,
+ * a client reads all of it to answer on a metrics location. They
+ * only grow, across reloads and restarts, except for the active
+ * connections. Each client counts those in a slot of its own, which
+ * the parent empties once the client is gone, however it went. The
+ * slots follow the counters, the parent adds more when a reload
+ * needs them while older generations still drain
+ */
+use crate::http::{Method, ResponseCode};
+use nix::libc;
+use std::fmt::Write;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const METHODS: [Method; 2] = [Method::GET, Method::HEAD];
+const CODES: [ResponseCode; 4] = [
+	ResponseCode::Ok, ResponseCode::PermissionDenied, ResponseCode::NotFound,
+	ResponseCode::InternalError,
+];
+/* Upper bounds of the filesystem latency buckets, in microseconds */
+const BUCKETS: [u64; 8] = [100, 250, 500, 1000, 2500, 5000, 10000, 50000];
+/* Every process maps room for this many slots, of which only those
+ * the parent added so far are backed
+ */
+const MAX_SLOTS: usize = 1 << 16;
+
+#[repr(C)]
+pub struct Counters {
+	/* By the parent */
+	pub accepted: AtomicU64,
+	/* How many slots there are, set once they are backed */
+	slots: AtomicU64,
+	/* By the clients */
+	requests: [[AtomicU64; CODES.len()]; METHODS.len()],
+	bytes: AtomicU64,
+	reused: AtomicU64,
+	/* Round trips to the filesystem process, the last bucket is
+	 * for everything slower
+	 */
+	latency: [AtomicU64; BUCKETS.len() + 1],
+	latency_sum: AtomicU64,
+	/* By the crypto process */
+	pub handshakes: AtomicU64,
+	pub handshake_failures: AtomicU64,
+}
+
+#[derive(Clone, Copy)]
+pub struct Metrics(&'static Counters);
+
+impl std::ops::Deref for Metrics {
+	type Target = Counters;
+
+	fn deref(&self) -> &Counters {
+		self.0
+	}
+}
+
+impl Metrics {
+	/* The parent's, with the descriptor to pass on to children */
+	pub fn create(slots: usize) -> std::io::Result<(Self, OwnedFd)> {
+		let fd = shared()?;
+		let metrics = Self::map(&fd)?;
+		metrics.grow(&fd, slots)?;
+		Ok((metrics, fd))
+	}
+
+	/* The memory stays mapped for as long as the process runs, it
+	 * starts out zeroed and zero is a valid counter
+	 */
+	pub fn map(fd: &OwnedFd) -> std::io::Result<Self> {
+		let len = std::mem::size_of::<Counters>() + MAX_SLOTS * std::mem::size_of::<AtomicU64>();
+		let ptr = unsafe {
+			libc::mmap(std::ptr::null_mut(), len,
+				libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd.as_raw_fd(), 0)
+		};
+		if ptr == libc::MAP_FAILED {
+			return Err(std::io::Error::last_os_error());
+		}
+		Ok(Self(unsafe { &*(ptr as *const Counters) }))
+	}
+
+	/* By the parent, before any client counts in the new slots */
+	pub fn grow(&self, fd: &OwnedFd, slots: usize) -> std::io::Result<()> {
+		if slots > MAX_SLOTS {
+			return Err(std::io::Error::other("too many clients to count connections for"));
+		}
+		let len = std::mem::size_of::<Counters>() + slots * std::mem::size_of::<AtomicU64>();
+		if unsafe { libc::ftruncate(fd.as_raw_fd(), len.try_into().expect("size")) } != 0 {
+			return Err(std::io::Error::last_os_error());
+		}
+		self.0.slots.store(slots as u64, Ordering::Release);
+		Ok(())
+	}
+
+	pub fn slots(&self) -> usize {
+		self.0.slots.load(Ordering::Acquire) as usize
+	}
+
+	fn slot(&self, slot: usize) -> &AtomicU64 {
+		assert!(slot < self.slots(), "slot out of range");
+		unsafe { &*(self.0 as *const Counters).add(1).cast::<AtomicU64>().add(slot) }
+	}
+
+	pub fn opened(&self, slot: usize) {
+		self.slot(slot).fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn closed(&self, slot: usize) {
+		self.slot(slot).fetch_sub(1, Ordering::Relaxed);
+	}
+
+	/* By the parent, for a client that exited */
+	pub fn reset(&self, slot: usize) {
+		self.slot(slot).store(0, Ordering::Relaxed);
+	}
+
+	/* By the parent, to pass over clients that are at their limit */
+	pub fn active_in(&self, slot: usize) -> u64 {
+		self.slot(slot).load(Ordering::Relaxed)
+	}
+
+	fn active(&self) -> u64 {
+		(0..self.slots()).map(|slot| self.active_in(slot)).sum()
+	}
+
+	/* reused is for requests after the first on a connection */
+	pub fn request(&self, method: Method, code: ResponseCode, bytes: u64, reused: bool) {
+		let method = METHODS.iter().position(|other| *other == method);
+		let code = CODES.iter().position(|other| other.status() == code.status());
+		if let (Some(method), Some(code)) = (method, code) {
+			self.requests[method][code].fetch_add(1, Ordering::Relaxed);
+		}
+		self.bytes.fetch_add(bytes, Ordering::Relaxed);
+		if reused {
+			self.reused.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+
+	pub fn latency(&self, latency: Duration) {
+		let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+		let bucket = BUCKETS.iter().position(|bound| micros <= *bound).unwrap_or(BUCKETS.len());
+		self.latency[bucket].fetch_add(1, Ordering::Relaxed);
+		self.latency_sum.fetch_add(micros, Ordering::Relaxed);
+	}
+
+	/* The Prometheus text exposition format */
+	pub fn text(&self) -> String {
+		fn head(text: &mut String, name: &str, kind: &str, help: &str) {
+			writeln!(text, "# HELP httpd_{name} {help}\n# TYPE httpd_{name} {kind}").unwrap();
+		}
+		let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+		let mut text = String::new();
+		head(&mut text, "connections_accepted_total", "counter", "Connections accepted.");
+		writeln!(text, "httpd_connections_accepted_total {}", get(&self.accepted)).unwrap();
+		head(&mut text, "connections_active", "gauge", "Connections being served.");
+		writeln!(text, "httpd_connections_active {}", self.active()).unwrap();
+		head(&mut text, "requests_total", "counter", "Requests answered, by method and status code.");
+		for (method, codes) in METHODS.iter().zip(&self.requests) {
+			for (code, count) in CODES.iter().zip(codes) {
+				writeln!(text, "httpd_requests_total{{method=\"{}\",code=\"{}\"}} {}",
+					method.as_str(), code.status(), get(count)).unwrap();
+			}
+		}
+		head(&mut text, "response_bytes_total", "counter", "Bytes of response bodies sent.");
+		writeln!(text, "httpd_response_bytes_total {}", get(&self.bytes)).unwrap();
+		head(&mut text, "keepalive_requests_total", "counter",
+			"Requests on a connection after its first one.");
+		writeln!(text, "httpd_keepalive_requests_total {}", get(&self.reused)).unwrap();
+		head(&mut text, "tls_handshakes_total", "counter", "TLS handshakes completed.");
+		writeln!(text, "httpd_tls_handshakes_total {}", get(&self.handshakes)).unwrap();
+		head(&mut text, "tls_handshake_failures_total", "counter", "TLS handshakes that failed.");
+		writeln!(text, "httpd_tls_handshake_failures_total {}", get(&self.handshake_failures))
+			.unwrap();
+		head(&mut text, "fs_request_duration_seconds", "histogram",
+			"Round trips to the filesystem process.");
+		let mut total = 0;
+		for (idx, count) in self.latency.iter().enumerate() {
+			total += get(count);
+			let bound = BUCKETS.get(idx).map_or("+Inf".to_string(), |micros| seconds(*micros));
+			writeln!(text, "httpd_fs_request_duration_seconds_bucket{{le=\"{bound}\"}} {total}")
+				.unwrap();
+		}
+		writeln!(text, "httpd_fs_request_duration_seconds_sum {}", seconds(get(&self.latency_sum)))
+			.unwrap();
+		writeln!(text, "httpd_fs_request_duration_seconds_count {total}").unwrap();
+		text
+	}
+
+	/* The same for people, requests without any are left out */
+	pub fn html(&self) -> String {
+		let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+		let mut html = String::new();
+		html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<title>httpd status</title>\n</head>\n");
+		html.push_str("<body>\n<table>\n");
+		let mut row = |name: &str, value: String| {
+			writeln!(html, "<tr><th align=left>{name}</th><td align=right>{value}</td></tr>").unwrap();
+		};
+		row("Connections accepted", get(&self.accepted).to_string());
+		row("Connections active", self.active().to_string());
+		for (method, codes) in METHODS.iter().zip(&self.requests) {
+			for (code, count) in CODES.iter().zip(codes) {
+				let count = get(count);
+				if count != 0 {
+					row(&format!("{} {}", method.as_str(), code.status()), count.to_string());
+				}
+			}
+		}
+		row("Bytes sent", get(&self.bytes).to_string());
+		row("Keep-alive requests", get(&self.reused).to_string());
+		row("TLS handshakes", get(&self.handshakes).to_string());
+		row("TLS handshake failures", get(&self.handshake_failures).to_string());
+		let count: u64 = self.latency.iter().map(get).sum();
+		let average = get(&self.latency_sum).checked_div(count).unwrap_or(0);
+		row("Filesystem round trips", count.to_string());
+		row("Average round trip", format!("{average} &micro;s"));
+		html.push_str("</table>\n</body>\n</html>\n");
+		html
+	}
+}
+
+fn seconds(micros: u64) -> String {
+	format!("{}", micros as f64 / 1e6)
+}
+
+/* Anonymous memory that can be passed on as a descriptor */
+#[cfg(target_os = "linux")]
+fn shared() -> std::io::Result<OwnedFd> {
+	let fd = unsafe { libc::memfd_create(c"httpd-metrics".as_ptr(), libc::MFD_CLOEXEC) };
+	if fd < 0 {
+		return Err(std::io::Error::last_os_error());
+	}
+	Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn shared() -> std::io::Result<OwnedFd> {
+	let name = format!("/httpd-metrics.{}\0", std::process::id());
+	let fd = unsafe {
+		libc::shm_open(name.as_ptr().cast(), libc::O_RDWR | libc::O_CREAT | libc::O_EXCL, 0o600)
+	};
+	if fd < 0 {
+		return Err(std::io::Error::last_os_error());
+	}
+	unsafe { libc::shm_unlink(name.as_ptr().cast()) };
+	Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[test]
+	fn text() {
+		let (metrics, _fd) = Metrics::create(8).unwrap();
+		metrics.accepted.fetch_add(3, Ordering::Relaxed);
+		metrics.opened(0);
+		metrics.opened(0);
+		metrics.opened(5);
+		metrics.closed(0);
+		metrics.request(Method::GET, ResponseCode::Ok, 100, false);
+		metrics.request(Method::GET, ResponseCode::Ok, 50, true);
+		metrics.request(Method::HEAD, ResponseCode::NotFound, 0, false);
+		metrics.latency(Duration::from_micros(200));
+		metrics.latency(Duration::from_secs(1));
+
+		let text = metrics.text();
+		let lines: Vec<&str> = text.lines().collect();
+		assert!(lines.contains(&"httpd_connections_accepted_total 3"));
+		assert!(lines.contains(&"httpd_connections_active 2"));
+		metrics.reset(5);
+		assert!(metrics.text().lines().any(|line| line == "httpd_connections_active 1"));
+		assert!(lines.contains(&"httpd_requests_total{method=\"GET\",code=\"200\"} 2"));
+		assert!(lines.contains(&"httpd_requests_total{method=\"HEAD\",code=\"404\"} 1"));
+		assert!(lines.contains(&"httpd_response_bytes_total 150"));
+		assert!(lines.contains(&"httpd_keepalive_requests_total 1"));
+		assert!(lines.contains(&"httpd_fs_request_duration_seconds_bucket{le=\"0.0001\"} 0"));
+		assert!(lines.contains(&"httpd_fs_request_duration_seconds_bucket{le=\"0.00025\"} 1"));
+		assert!(lines.contains(&"httpd_fs_request_duration_seconds_bucket{le=\"+Inf\"} 2"));
+		assert!(lines.contains(&"httpd_fs_request_duration_seconds_sum 1.0002"));
+		assert!(lines.contains(&"httpd_fs_request_duration_seconds_count 2"));
+		/* Every sample belongs to the metric declared before it */
+		let mut declared = "";
+		for line in lines {
+			match line.strip_prefix("# TYPE ") {
+				Some(rest) => declared = rest.split(' ').next().unwrap(),
+				None if !line.starts_with('#') => assert!(line.starts_with(declared), "{line}"),
+				None => {}
+			}
+		}
+	}
+}
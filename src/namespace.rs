@@ -0,0 +1,152 @@
+/* Linux namespaces for the children, which only ever receive file
+ * descriptors: without a network of their own they cannot reach
+ * anything, and in a PID namespace of their own they see no other
+ * processes
+ */
+use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::signal::{self, SigHandler, SigSet, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{fork, ForkResult, Pid};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+/* Signals the reaper passes on, the parent only sends SIGTERM */
+const FORWARDED: [Signal; 5] = [
+	Signal::SIGTERM, Signal::SIGINT, Signal::SIGHUP, Signal::SIGUSR1, Signal::SIGUSR2,
+];
+
+pub enum Isolated {
+	/* This process goes on to serve, in whichever namespaces
+	 * could be entered
+	 */
+	Child,
+	/* Left behind in the old PID namespace, see reap() */
+	Reaper(Pid),
+}
+
+/* Enters each namespace on its own, so that one the kernel lacks
+ * does not cost the others. Has to be called before any threads
+ * exist, as it forks
+ */
+pub fn isolate() -> (Isolated, Vec<(&'static str, nix::Error)>) {
+	let mut failed = Vec::new();
+	let namespaces = [
+		("network", CloneFlags::CLONE_NEWNET),
+		("ipc", CloneFlags::CLONE_NEWIPC),
+		("mount", CloneFlags::CLONE_NEWNS),
+		("pid", CloneFlags::CLONE_NEWPID),
+	];
+	let mut pid = false;
+	for (name, flag) in namespaces {
+		match unshare(flag) {
+			Ok(()) => pid |= flag == CloneFlags::CLONE_NEWPID,
+			Err(err) => failed.push((name, err)),
+		}
+	}
+	/* Mounts are not passed back and forth with the old namespace,
+	 * if entering it failed this changes nothing
+	 */
+	let _ = mount(None::<&str>, "/", None::<&str>, MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+		None::<&str>);
+	if !pid {
+		return (Isolated::Child, failed);
+	}
+
+	/* Only processes started from now on are in the new PID
+	 * namespace. The signals are blocked first so that none is lost
+	 * before the reaper waits for them
+	 */
+	let mut signals = SigSet::empty();
+	for signal in FORWARDED {
+		signals.add(signal);
+	}
+	signals.add(Signal::SIGCHLD);
+	let mut old = SigSet::empty();
+	if let Err(err) = signal::sigprocmask(signal::SigmaskHow::SIG_BLOCK, Some(&signals),
+	Some(&mut old)) {
+		failed.push(("pid", err));
+		return (Isolated::Child, failed);
+	}
+	let reaper = pidfd(nix::unistd::getpid());
+	let isolated = match unsafe { fork() } {
+		Ok(ForkResult::Parent { child }) => Isolated::Reaper(child),
+		Ok(ForkResult::Child) => {
+			let _ = nix::sys::prctl::set_pdeathsig(Signal::SIGKILL);
+			/* The reaper may have died before that. getppid() cannot
+			 * tell, it is 0 for a parent outside the PID namespace
+			 */
+			if reaper.as_ref().is_some_and(exited) {
+				std::process::exit(1);
+			}
+			Isolated::Child
+		}
+		Err(err) => {
+			failed.push(("pid", err));
+			Isolated::Child
+		}
+	};
+	if let Isolated::Child = isolated {
+		let _ = signal::sigprocmask(signal::SigmaskHow::SIG_SETMASK, Some(&old), None);
+	}
+	(isolated, failed)
+}
+
+fn pidfd(pid: Pid) -> Option<OwnedFd> {
+	let fd = unsafe { nix::libc::syscall(nix::libc::SYS_pidfd_open, pid.as_raw(), 0) };
+	(fd >= 0).then(|| unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/* A pidfd is readable once its process exited */
+fn exited(pidfd: &OwnedFd) -> bool {
+	let mut poll = nix::libc::pollfd { fd: pidfd.as_raw_fd(), events: nix::libc::POLLIN, revents: 0 };
+	unsafe { nix::libc::poll(&mut poll, 1, 0) > 0 }
+}
+
+/* Stands in for the child towards the parent: signals are passed on
+ * and the child's exit is passed back as this process's own. The
+ * child is killed should this process die. It runs as the same user
+ * as the child, with a seccomp filter for nothing but that
+ */
+pub fn reap(child: Pid) -> ! {
+	if let Err(err) = crate::seccomp::reaper() {
+		crate::log::warning!("reaper: {err}");
+	}
+	/* Channels stay open only in the child */
+	unsafe {
+		nix::libc::syscall(nix::libc::SYS_close_range, 3, u32::MAX, 0);
+	}
+	let mut signals = SigSet::empty();
+	for signal in FORWARDED {
+		signals.add(signal);
+	}
+	signals.add(Signal::SIGCHLD);
+	loop {
+		match signals.wait() {
+			Ok(Signal::SIGCHLD) => match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+				Ok(WaitStatus::Exited(_, code)) => exit(code),
+				Ok(WaitStatus::Signaled(_, signal, _)) => {
+					unsafe {
+						let _ = signal::signal(signal, SigHandler::SigDfl);
+					}
+					let mut unblock = SigSet::empty();
+					unblock.add(signal);
+					let _ = unblock.thread_unblock();
+					let _ = signal::raise(signal);
+					exit(128 + signal as i32);
+				}
+				_ => {}
+			},
+			Ok(signal) => {
+				let _ = signal::kill(child, signal);
+			}
+			Err(_) => {}
+		}
+	}
+}
+
+/* Without the standard library's cleanup, which takes down the
+ * signal stack with calls the filter does not allow
+ */
+fn exit(code: i32) -> ! {
+	unsafe { nix::libc::_exit(code) }
+}
@@ -0,0 +1,77 @@
+/* Networks in address/prefix notation, for deciding which peers are
+ * trusted or allowed
+ */
+use serde_derive::{Serialize, Deserialize};
+use std::net::IpAddr;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Network {
+	addr: IpAddr,
+	prefix: u8,
+}
+
+impl Network {
+	/* IPv4 addresses mapped into IPv6 belong to IPv4 networks */
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		match (self.addr, ip.to_canonical()) {
+			(IpAddr::V4(net), IpAddr::V4(ip)) => {
+				let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+				u32::from(net) & mask == u32::from(ip) & mask
+			}
+			(IpAddr::V6(net), IpAddr::V6(ip)) => {
+				let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+				u128::from(net) & mask == u128::from(ip) & mask
+			}
+			_ => false,
+		}
+	}
+}
+
+/* Without a prefix the network is the single address */
+impl std::str::FromStr for Network {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (addr, prefix) = match s.split_once('/') {
+			Some((addr, prefix)) => (addr, Some(prefix)),
+			None => (s, None),
+		};
+		let addr: IpAddr = addr.parse().map_err(|_| ())?;
+		let bits = if addr.is_ipv4() { 32 } else { 128 };
+		let prefix = match prefix {
+			Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= bits).ok_or(())?,
+			None => bits,
+		};
+		Ok(Self { addr, prefix })
+	}
+}
+
+impl fmt::Display for Network {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}/{}", self.addr, self.prefix)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[test]
+	fn contains() {
+		let net: Network = "192.0.2.0/24".parse().unwrap();
+		assert!(net.contains("192.0.2.77".parse().unwrap()));
+		assert!(net.contains("::ffff:192.0.2.1".parse().unwrap()));
+		assert!(!net.contains("192.0.3.1".parse().unwrap()));
+		assert!(!net.contains("2001:db8::1".parse().unwrap()));
+
+		let net: Network = "2001:db8::/32".parse().unwrap();
+		assert!(net.contains("2001:db8:1::1".parse().unwrap()));
+		assert!(!net.contains("2001:db9::1".parse().unwrap()));
+
+		let any: Network = "0.0.0.0/0".parse().unwrap();
+		assert!(any.contains("203.0.113.9".parse().unwrap()));
+		assert_eq!("::1".parse::<Network>().unwrap().to_string(), "::1/128");
+		assert!("192.0.2.0/33".parse::<Network>().is_err());
+		assert!("example.com".parse::<Network>().is_err());
+	}
+}
@@ -1,82 +1,347 @@
 use tokio_seqpacket::{ancillary::AncillaryMessageWriter, UnixSeqpacket};
 use tokio_seqpacket::ancillary::OwnedAncillaryMessage;
 use tokio::process;
+use std::sync::Arc;
 use tokio_command_fds::{CommandFdExt, FdMapping};
-use std::os::fd::{OwnedFd, AsFd};
-use nix::sys::signal;
-use nix::unistd::User;
+use std::os::fd::{OwnedFd, AsFd, AsRawFd};
+use nix::sys::signal::Signal;
+use nix::unistd::{Gid, Group, User};
+use std::os::unix::fs::MetadataExt;
+use thiserror::Error;
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 const PROCESS_FD: std::os::fd::RawFd = 3;
+pub const LOG_FD: std::os::fd::RawFd = 4;
 
+static UNPRIVILEGED: AtomicBool = AtomicBool::new(false);
+
+/* Without root there is no chroot and no user to change to, the
+ * processes are still separated and the filesystem process keeps
+ * requests beneath the document root by itself
+ */
+pub fn set_unprivileged() {
+	UNPRIVILEGED.store(true, Ordering::Relaxed);
+}
+
+pub fn unprivileged() -> bool {
+	UNPRIVILEGED.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Error)]
+pub enum PledgeError {
+	#[error("{0}")]
+	Pledge(#[from] pledge::Error),
+	#[error("{0}")]
+	Seccomp(#[from] crate::seccomp::Error),
+	#[cfg(target_os = "linux")]
+	#[error("{0}")]
+	Landlock(#[from] crate::landlock::Error),
+}
+
+/* On Linux a seccomp filter is installed from the promises instead.
+ * Unlike pledge() without execpromises it would carry over to
+ * executed programs, so the parent, which promises exec, has none
+ */
 pub fn pledge<'a, 'b, T, E> (promises: T, exec_promises: E)
--> Result<(), pledge::Error> 
+-> Result<(), PledgeError> 
 where T: Into<Option<&'a str>>, E: Into<Option<&'a str>> {
-	pledge::pledge(promises, exec_promises).or_else(pledge::Error::ignore_platform)
+	let promises = promises.into();
+	pledge::pledge(promises, exec_promises).or_else(pledge::Error::ignore_platform)?;
+	#[cfg(target_os = "linux")]
+	{
+		let locked = promises.is_some_and(|promises| {
+			!promises.split_whitespace().any(|promise| promise == "unveil")
+		});
+		if locked && crate::landlock::lock()? == crate::landlock::Status::Unsupported {
+			crate::log::warning!("landlock is not available, unveiled paths are not enforced");
+		}
+	}
+	if let Some(promises) = promises {
+		if !promises.split_whitespace().any(|promise| promise == "exec") {
+			crate::seccomp::install(promises)?;
+		}
+	}
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum UnveilError {
+	#[error("{0}")]
+	Unveil(#[from] unveil::Error),
+	#[cfg(target_os = "linux")]
+	#[error("{0}")]
+	Landlock(#[from] crate::landlock::Error),
 }
 
-pub fn unveil(path: impl AsRef<[u8]>, permissions: &str) -> Result<(), unveil::Error>
+/* On Linux the paths are given to landlock, which enforces them
+ * once pledge() drops the unveil promise
+ */
+pub fn unveil(path: impl AsRef<[u8]>, permissions: &str) -> Result<(), UnveilError>
 {
-	unveil::unveil(path, permissions).or_else(unveil::Error::ignore_platform)
+	unveil::unveil(&path, permissions).or_else(unveil::Error::ignore_platform)?;
+	#[cfg(target_os = "linux")]
+	{
+		use std::os::unix::ffi::OsStrExt;
+		crate::landlock::unveil(std::ffi::OsStr::from_bytes(path.as_ref()), permissions)?;
+	}
+	Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum PrivError {
+	#[error("user \"{0}\" does not exist")]
+	NoUser(String),
+	#[error("group \"{0}\" does not exist")]
+	NoGroup(String),
+	#[error("chroot {0}: not a directory")]
+	NotDirectory(String),
+	#[error("chroot {0}: not owned by root")]
+	NotRootOwned(String),
+	#[error("chroot {0}: writable by user \"{1}\"")]
+	Writable(String, String),
+	#[error("{path}: {source}")]
+	Io {
+		path: String,
+		source: std::io::Error,
+	},
+	#[error("{call}: {source}")]
+	Sys {
+		call: &'static str,
+		source: nix::Error,
+	},
 }
 
-pub fn privdrop(root: &str, user: &str) -> std::io::Result<()> {
-	let user = User::from_name(user)?
-		.ok_or::<std::io::Error>(std::io::ErrorKind::NotFound.into())?;
-	nix::unistd::chroot(root).expect("chroot");
-	nix::unistd::chdir("/").expect("chdir");
-	nix::unistd::setgroups(&[user.gid]).expect("setgroups");
-	nix::unistd::setresgid(user.gid, user.gid, user.gid).expect("setgid");
-	nix::unistd::setresuid(user.uid, user.uid, user.uid).expect("setuid");
+fn sys(call: &'static str) -> impl FnOnce(nix::Error) -> PrivError {
+	move |source| PrivError::Sys { call, source }
+}
+
+/* The user the children run as, with the group if one is given
+ * or else the primary group of the user
+ */
+pub fn credentials(user: &str, group: Option<&str>) -> Result<(User, Gid), PrivError> {
+	let user = User::from_name(user).map_err(sys("getpwnam"))?
+		.ok_or_else(|| PrivError::NoUser(user.to_string()))?;
+	let gid = match group {
+		Some(group) => Group::from_name(group).map_err(sys("getgrnam"))?
+			.ok_or_else(|| PrivError::NoGroup(group.to_string()))?
+			.gid,
+		None => user.gid,
+	};
+	Ok((user, gid))
+}
+
+/* A chroot the user can write to would let it change what it is
+ * confined to, so it has to belong to root
+ */
+pub fn check_chroot(root: &str, user: &User, gid: Gid) -> Result<(), PrivError> {
+	let metadata = std::fs::metadata(root)
+		.map_err(|source| PrivError::Io { path: root.to_string(), source })?;
+	if !metadata.is_dir() {
+		return Err(PrivError::NotDirectory(root.to_string()));
+	}
+	if metadata.uid() != 0 {
+		return Err(PrivError::NotRootOwned(root.to_string()));
+	}
+	let mode = metadata.mode();
+	if mode & 0o002 != 0 || (mode & 0o020 != 0 && metadata.gid() == gid.as_raw()) {
+		return Err(PrivError::Writable(root.to_string(), user.name.clone()));
+	}
+	Ok(())
+}
+
+pub fn privdrop(root: &str, user: &str, group: Option<&str>) -> Result<(), PrivError> {
+	let (user, gid) = credentials(user, group)?;
+	nix::unistd::chroot(root).map_err(sys("chroot"))?;
+	nix::unistd::chdir("/").map_err(sys("chdir"))?;
+	nix::unistd::setgroups(&[gid]).map_err(sys("setgroups"))?;
+	nix::unistd::setresgid(gid, gid, gid).map_err(sys("setresgid"))?;
+	nix::unistd::setresuid(user.uid, user.uid, user.uid).map_err(sys("setresuid"))?;
 	Ok(())
 }
 
+/* Forks and starts a new session, only the child returns */
+pub fn daemonize() -> std::io::Result<()> {
+	match unsafe { nix::unistd::fork() }? {
+		nix::unistd::ForkResult::Parent { .. } => std::process::exit(0),
+		nix::unistd::ForkResult::Child => {}
+	}
+	nix::unistd::setsid()?;
+	/* The directory it was started in may be unmounted */
+	nix::unistd::chdir("/")?;
+	Ok(())
+}
+
+/* Points stdin, stdout and stderr to /dev/null, the children are
+ * started with these
+ */
+pub fn detach_stdio() -> std::io::Result<()> {
+	let null = std::fs::OpenOptions::new().read(true).write(true).open("/dev/null")?;
+	for fd in 0..=2 {
+		nix::unistd::dup2(null.as_raw_fd(), fd)?;
+	}
+	Ok(())
+}
+
+/* The pidfile stays locked for as long as the server runs, so that a
+ * second one refuses to start, it is removed on a clean exit
+ */
+pub struct Pidfile {
+	path: String,
+	_file: std::fs::File,
+	unlink: bool,
+}
+
+impl Pidfile {
+	pub fn create(path: &str) -> std::io::Result<Self> {
+		/* Truncated only once locked, it may belong to a running httpd */
+		let mut file = std::fs::OpenOptions::new().write(true).create(true).truncate(false)
+			.open(path)?;
+		flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock).map_err(|err| match err {
+			Errno::EWOULDBLOCK => std::io::Error::new(std::io::ErrorKind::WouldBlock,
+				"already locked by another httpd"),
+			err => err.into(),
+		})?;
+		file.set_len(0)?;
+		writeln!(file, "{}", std::process::id())?;
+		Ok(Self { path: path.to_string(), _file: file, unlink: true })
+	}
+
+	/* For the copy that stays in the parent on forking the helper,
+	 * the lock is held by both, the file is removed by the helper
+	 */
+	pub fn disown(mut self) {
+		self.unlink = false;
+	}
+}
+
+impl Drop for Pidfile {
+	fn drop(&mut self) {
+		if !self.unlink {
+			return;
+		}
+		let _ = std::fs::remove_file(&self.path);
+	}
+}
+
 pub struct ProcessBuilder<'a> {
 	path: &'a str,
 	name: &'a str,
+	user: &'a str,
+	group: Option<&'a str>,
+	chroot: &'a str,
+	seccomp: crate::seccomp::Mode,
 }
 
 impl <'a> ProcessBuilder<'a> {
-	pub fn new(path: &'a str, name: &'a str) -> Self {
-		Self { path, name }
+	pub fn new(path: &'a str, name: &'a str, user: &'a str, chroot: &'a str) -> Self {
+		Self { path, name, user, group: None, chroot, seccomp: crate::seccomp::Mode::Kill }
 	}
-	pub fn build(self) -> std::io::Result<Process> {
+	pub fn group(mut self, group: Option<&'a str>) -> Self {
+		self.group = group;
+		self
+	}
+	pub fn seccomp(mut self, mode: crate::seccomp::Mode) -> Self {
+		self.seccomp = mode;
+		self
+	}
+	/* Returns the parent's end of the channel to the process */
+	pub fn spawn(self) -> std::io::Result<(OwnedFd, process::Child)> {
 		let (a, socket) = UnixSeqpacket::pair()?;
 		let mut command = process::Command::new(self.path);
 		command.kill_on_drop(true);
 		command.arg0("httpd");
-		command.args(["-p", self.name]);
-		command.fd_mappings(vec![
+		command.args(["-p", self.name, "-u", self.user, "-c", self.chroot]);
+		if let Some(group) = self.group {
+			command.args(["-g", group]);
+		}
+		if unprivileged() {
+			command.arg("-U");
+		}
+		if self.seccomp != crate::seccomp::Mode::Kill {
+			command.args(["-s", &self.seccomp.to_string()]);
+		}
+		for _ in 0..crate::log::verbose() {
+			command.arg("-v");
+		}
+		let mut mappings = vec![
 			FdMapping {
 				parent_fd: a.as_raw_fd(),
 				child_fd: PROCESS_FD,
 			},
-		]).unwrap();
+		];
+		if let Some(log) = crate::log::child_fd() {
+			mappings.push(FdMapping {
+				parent_fd: log.as_raw_fd(),
+				child_fd: LOG_FD,
+			});
+		}
+		command.fd_mappings(mappings).unwrap();
 		let child = command.spawn()?;
-		Ok(Process {
-			peer: Peer { socket },
-			child
-		})
+		Ok((socket.into(), child))
 	}
 }
 
+/* A child the helper started, it is waited for and signalled there */
 pub struct Process {
 	peer: Peer,
-	child: process::Child,
+	id: u64,
+	pid: u32,
+	helper: Arc<crate::helper::Helper>,
 }
 
 impl Process {
-	pub async fn end(mut self) -> std::io::Result<()> {
-		let pid = self.child.id().unwrap();
-		let pid: i32 = pid.try_into().unwrap();
-		let pid = nix::unistd::Pid::from_raw(pid);
-		signal::kill(pid, signal::Signal::SIGTERM).expect("kill");
-
-		self.child.wait().await?;
+	pub fn new(peer: Peer, id: u64, pid: u32, helper: Arc<crate::helper::Helper>) -> Self {
+		Self { peer, id, pid, helper }
+	}
+	/* Asks the process to finish what it is doing and exit */
+	pub async fn terminate(&self) {
+		if self.try_wait().is_none() {
+			let _ = self.helper.signal(self.id, Signal::SIGTERM).await;
+		}
+	}
+	pub async fn kill(&self) {
+		if self.try_wait().is_none() {
+			let _ = self.helper.signal(self.id, Signal::SIGKILL).await;
+		}
+	}
+	/* Waits for the process to exit until the deadline, after which
+	 * it is killed
+	 */
+	pub async fn end(self, deadline: tokio::time::Instant) -> std::io::Result<()> {
+		if tokio::time::timeout_at(deadline, self.helper.wait(self.id)).await.is_err() {
+			self.helper.signal(self.id, Signal::SIGKILL).await?;
+			self.helper.wait(self.id).await;
+		}
 		Ok(())
 	}
+	/* Closes the channel to the process, it is expected to
+	 * exit by itself once it notices
+	 */
+	pub fn close(&self) -> std::io::Result<()> {
+		self.peer.socket.shutdown(std::net::Shutdown::Both)
+	}
+	pub fn try_wait(&self) -> Option<std::process::ExitStatus> {
+		self.helper.status(self.id)
+	}
 	pub fn peer(&self) -> &Peer {
 		&self.peer
 	}
+	/* None once the process exited */
+	pub fn id(&self) -> Option<u32> {
+		self.try_wait().is_none().then_some(self.pid)
+	}
+}
+
+/* Without kill on drop, a process is left to exit once its channel
+ * is closed
+ */
+impl Drop for Process {
+	fn drop(&mut self) {
+		self.helper.forget(self.id);
+	}
 }
 
 pub struct Peer {
@@ -98,14 +363,15 @@ impl Peer {
 	pub fn socket(&self) -> &UnixSeqpacket {
 		&self.socket
 	}
-	pub async fn send_fds (&self, fds: &[OwnedFd])
+	pub async fn send_fds (&self, fds: &[OwnedFd], data: &[u8])
 	-> std::io::Result<()> 
 	{
 		let fds: Vec<_> = fds.iter().map(|fd| fd.as_fd()).collect();
 		let mut buf: [u8; 128] = [0; 128];
+		let slice = std::io::IoSlice::new(data);
 		let mut writer = AncillaryMessageWriter::new(&mut buf);
 		writer.add_fds(&fds).expect("add_fds");
-		self.socket.send_vectored_with_ancillary(&[], &mut writer).await?;
+		self.socket.send_vectored_with_ancillary(&[slice], &mut writer).await?;
 		Ok(())
 	}
 	pub async fn send_with_fd<T> (&self, fd: T, data: &[u8])
@@ -135,9 +401,24 @@ impl Peer {
 					.ok_or::<std::io::Error>(std::io::ErrorKind::NotFound.into())?;
 				Ok((len, fd))
 			}
+			_ if len == 0 => Err(std::io::ErrorKind::UnexpectedEof.into()),
 			_ => Err(std::io::ErrorKind::NotFound.into()),
 		}
 	}
+	/* For messages that only sometimes carry a descriptor */
+	pub async fn recv_with_optional_fd(&self, data: &mut [u8])
+	-> std::io::Result<(usize, Option<OwnedFd>)>
+	{
+		let mut buffer: [u8; 128] = [0; 128];
+		let slice = std::io::IoSliceMut::new(data);
+		let (len, ancillary) = self.socket
+			.recv_vectored_with_ancillary(&mut [slice], &mut buffer).await?;
+		let fd = ancillary.into_messages().find_map(|message| match message {
+			OwnedAncillaryMessage::FileDescriptors(mut fds) => fds.next(),
+			_ => None,
+		});
+		Ok((len, fd))
+	}
 	pub async fn recv_with_fds(&self, data: &mut [u8]) 
 	-> std::io::Result<(usize, (OwnedFd, OwnedFd))> 
 	{
@@ -155,6 +436,7 @@ impl Peer {
 					.ok_or::<std::io::Error>(std::io::ErrorKind::NotFound.into())?;
 				Ok((len, (fd, fd2)))
 			}
+			_ if len == 0 => Err(std::io::ErrorKind::UnexpectedEof.into()),
 			_ => Err(std::io::ErrorKind::NotFound.into()),
 		}
 	}
@@ -0,0 +1,132 @@
+/* The PROXY protocol, versions 1 and 2, with which a load balancer
+ * passes on the address of the client ahead of the connection's
+ * own bytes
+ */
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/* How long the proxy has to send the header */
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/* The longest version 1 header, with the line end */
+const V1_MAX: usize = 107;
+
+fn invalid(what: &str) -> Error {
+	Error::new(ErrorKind::InvalidData, format!("PROXY protocol: {what}"))
+}
+
+/* Reads the header and nothing more, so reader should be buffered.
+ * None is the proxy speaking for itself, as in health checks, or
+ * for a client without an address
+ */
+pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Option<SocketAddr>> {
+	match reader.read_u8().await? {
+		b'P' => {
+			let mut line = vec![b'P'];
+			while !line.ends_with(b"\r\n") {
+				if line.len() == V1_MAX {
+					return Err(invalid("header too long"));
+				}
+				line.push(reader.read_u8().await?);
+			}
+			v1(&line[..line.len() - 2])
+		}
+		b'\r' => {
+			let mut header = [0u8; 16];
+			header[0] = b'\r';
+			reader.read_exact(&mut header[1..]).await?;
+			if header[..12] != SIGNATURE {
+				return Err(invalid("bad signature"));
+			}
+			let len = u16::from_be_bytes([header[14], header[15]]);
+			let mut rest = vec![0u8; len.into()];
+			reader.read_exact(&mut rest).await?;
+			v2(header[12], header[13], &rest)
+		}
+		_ => Err(invalid("no header")),
+	}
+}
+
+/* PROXY TCP4|TCP6 source destination sport dport, or PROXY UNKNOWN */
+fn v1(line: &[u8]) -> std::io::Result<Option<SocketAddr>> {
+	let line = std::str::from_utf8(line).map_err(|_| invalid("bad header"))?;
+	let mut words = line.split(' ');
+	if words.next() != Some("PROXY") {
+		return Err(invalid("bad header"));
+	}
+	let v6 = match words.next() {
+		Some("TCP4") => false,
+		Some("TCP6") => true,
+		Some("UNKNOWN") => return Ok(None),
+		_ => return Err(invalid("bad protocol")),
+	};
+	let source: IpAddr = words.next().and_then(|addr| addr.parse().ok())
+		.ok_or_else(|| invalid("bad address"))?;
+	let _destination = words.next();
+	let port: u16 = words.next().and_then(|port| port.parse().ok())
+		.ok_or_else(|| invalid("bad port"))?;
+	if source.is_ipv6() != v6 {
+		return Err(invalid("bad address"));
+	}
+	Ok(Some(SocketAddr::new(source, port)))
+}
+
+/* Addresses other than TCP over IPv4 and IPv6 are of no use, neither
+ * are the TLVs following them
+ */
+fn v2(command: u8, family: u8, rest: &[u8]) -> std::io::Result<Option<SocketAddr>> {
+	match command {
+		0x20 => return Ok(None),
+		0x21 => {}
+		_ => return Err(invalid("bad version or command")),
+	}
+	let addr = match family {
+		0x11 if rest.len() >= 12 => {
+			let ip: [u8; 4] = rest[..4].try_into().unwrap();
+			SocketAddr::new(Ipv4Addr::from(ip).into(), u16::from_be_bytes([rest[8], rest[9]]))
+		}
+		0x21 if rest.len() >= 36 => {
+			let ip: [u8; 16] = rest[..16].try_into().unwrap();
+			SocketAddr::new(Ipv6Addr::from(ip).into(), u16::from_be_bytes([rest[32], rest[33]]))
+		}
+		0x11 | 0x21 => return Err(invalid("short address")),
+		_ => return Ok(None),
+	};
+	Ok(Some(addr))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	async fn parse(mut input: &[u8]) -> (std::io::Result<Option<SocketAddr>>, usize) {
+		let result = super::read(&mut input).await;
+		(result, input.len())
+	}
+	#[tokio::test]
+	async fn read() {
+		let (result, left) = parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 4711 443\r\nGET").await;
+		assert_eq!(result.unwrap(), Some("192.0.2.1:4711".parse().unwrap()));
+		assert_eq!(left, 3);
+		let (result, _) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 80\r\n").await;
+		assert_eq!(result.unwrap(), Some("[2001:db8::1]:4711".parse().unwrap()));
+		let (result, _) = parse(b"PROXY UNKNOWN\r\n").await;
+		assert_eq!(result.unwrap(), None);
+		assert!(parse(b"PROXY TCP4 2001:db8::1 ::1 1 2\r\n").await.0.is_err());
+		assert!(parse(&[b'P'; 200]).await.0.is_err());
+		assert!(parse(b"GET / HTTP/1.1\r\n").await.0.is_err());
+
+		let mut v2 = SIGNATURE.to_vec();
+		v2.extend([0x21, 0x11, 0, 15, 192, 0, 2, 1, 198, 51, 100, 1, 0x12, 0x67, 0x01, 0xbb]);
+		v2.extend([0x04, 0, 0]);
+		v2.extend(b"GET");
+		let (result, left) = parse(&v2).await;
+		assert_eq!(result.unwrap(), Some("192.0.2.1:4711".parse().unwrap()));
+		assert_eq!(left, 3);
+		let mut local = SIGNATURE.to_vec();
+		local.extend([0x20, 0x00, 0, 0]);
+		assert_eq!(parse(&local).await.0.unwrap(), None);
+	}
+}
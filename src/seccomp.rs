@@ -0,0 +1,370 @@
+/* Seccomp filters standing in for pledge(2) on Linux, built from
+ * the same promise strings. Promises are mapped to the system calls
+ * the standard library and tokio make for them
+ */
+use serde_derive::{Serialize, Deserialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::fmt;
+use thiserror::Error;
+
+/* What happens to a process making a system call it did not promise */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Mode {
+	Kill,
+	/* The call is allowed and the kernel logs it, for finding out
+	 * what a filter is missing
+	 */
+	Log,
+}
+
+impl fmt::Display for Mode {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Mode::Kill => write!(f, "kill"),
+			Mode::Log => write!(f, "log"),
+		}
+	}
+}
+
+static LOG_ONLY: AtomicBool = AtomicBool::new(false);
+
+pub fn set_mode(mode: Mode) {
+	LOG_ONLY.store(mode == Mode::Log, Ordering::Relaxed);
+}
+
+pub fn mode() -> Mode {
+	match LOG_ONLY.load(Ordering::Relaxed) {
+		true => Mode::Log,
+		false => Mode::Kill,
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+	#[error("promise \"{0}\" has no seccomp filter")]
+	Promise(String),
+	#[error("seccomp: {0}")]
+	Install(std::io::Error),
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod filter {
+	use super::{Error, Mode};
+	use nix::libc;
+
+	#[cfg(target_arch = "x86_64")]
+	const AUDIT_ARCH: u32 = 0xc000003e;
+	#[cfg(target_arch = "aarch64")]
+	const AUDIT_ARCH: u32 = 0xc00000b7;
+
+	/* Offsets into struct seccomp_data, arguments are read by their
+	 * low half
+	 */
+	const NR: u32 = 0;
+	const ARCH: u32 = 4;
+	const fn arg(idx: u32) -> u32 {
+		16 + idx * 8
+	}
+
+	const STDIO: &[libc::c_long] = &[
+		libc::SYS_read, libc::SYS_write, libc::SYS_readv, libc::SYS_writev,
+		libc::SYS_pread64, libc::SYS_pwrite64, libc::SYS_lseek, libc::SYS_close,
+		libc::SYS_ftruncate,
+		/* glibc implements fstat() with it */
+		libc::SYS_newfstatat,
+		#[cfg(target_arch = "x86_64")]
+		libc::SYS_fstat,
+		libc::SYS_fcntl, libc::SYS_dup, libc::SYS_dup3, libc::SYS_pipe2,
+		libc::SYS_brk, libc::SYS_mmap, libc::SYS_munmap, libc::SYS_mremap, libc::SYS_mprotect,
+		libc::SYS_madvise,
+		libc::SYS_rt_sigaction, libc::SYS_rt_sigprocmask, libc::SYS_rt_sigreturn,
+		libc::SYS_sigaltstack, libc::SYS_restart_syscall,
+		libc::SYS_exit, libc::SYS_exit_group, libc::SYS_getpid, libc::SYS_gettid,
+		libc::SYS_getuid, libc::SYS_geteuid, libc::SYS_getgid, libc::SYS_getegid,
+		libc::SYS_tgkill, libc::SYS_futex, libc::SYS_set_robust_list, libc::SYS_rseq,
+		libc::SYS_sched_yield, libc::SYS_sched_getaffinity, libc::SYS_getrandom,
+		libc::SYS_clock_gettime, libc::SYS_clock_nanosleep, libc::SYS_nanosleep,
+		libc::SYS_gettimeofday,
+		libc::SYS_epoll_create1, libc::SYS_epoll_ctl, libc::SYS_epoll_pwait,
+		#[cfg(target_arch = "x86_64")]
+		libc::SYS_epoll_wait,
+		#[cfg(target_arch = "x86_64")]
+		libc::SYS_poll,
+		libc::SYS_ppoll, libc::SYS_eventfd2,
+		/* Sockets that are already open, passing descriptors over
+		 * them cannot be told apart, so sendfd and recvfd add nothing
+		 */
+		libc::SYS_sendto, libc::SYS_recvfrom, libc::SYS_sendmsg, libc::SYS_recvmsg,
+		libc::SYS_shutdown, libc::SYS_getsockopt,
+		libc::SYS_getsockname, libc::SYS_getpeername,
+	];
+
+	const RPATH: &[libc::c_long] = &[
+		libc::SYS_statx, libc::SYS_readlinkat, libc::SYS_getdents64, libc::SYS_faccessat,
+		libc::SYS_faccessat2, libc::SYS_getcwd,
+		#[cfg(target_arch = "x86_64")]
+		libc::SYS_readlink,
+		#[cfg(target_arch = "x86_64")]
+		libc::SYS_stat,
+		#[cfg(target_arch = "x86_64")]
+		libc::SYS_lstat,
+		#[cfg(target_arch = "x86_64")]
+		libc::SYS_access,
+	];
+
+	/* All the reaper in namespace.rs does once it is in place: close
+	 * the channels, wait for signals, pass them on and exit the way
+	 * its child did
+	 */
+	const REAPER: &[libc::c_long] = &[
+		libc::SYS_close_range, libc::SYS_rt_sigtimedwait, libc::SYS_wait4, libc::SYS_kill,
+		libc::SYS_tgkill, libc::SYS_getpid, libc::SYS_gettid, libc::SYS_rt_sigaction,
+		libc::SYS_rt_sigprocmask, libc::SYS_rt_sigreturn, libc::SYS_restart_syscall,
+		libc::SYS_exit, libc::SYS_exit_group,
+	];
+
+	fn stmt(code: u32, k: u32) -> libc::sock_filter {
+		libc::sock_filter { code: code as u16, jt: 0, jf: 0, k }
+	}
+
+	fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+		libc::sock_filter { code: code as u16, jt, jf, k }
+	}
+
+	fn load(offset: u32) -> libc::sock_filter {
+		stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset)
+	}
+
+	fn ret(action: u32) -> libc::sock_filter {
+		stmt(libc::BPF_RET | libc::BPF_K, action)
+	}
+
+	/* Allows nr outright, each check falls through to the next */
+	fn allow(program: &mut Vec<libc::sock_filter>, nr: libc::c_long) {
+		program.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, nr as u32, 0, 1));
+		program.push(ret(libc::SECCOMP_RET_ALLOW));
+	}
+
+	/* Allows nr when none of the bits in mask are set in an argument */
+	fn allow_without(program: &mut Vec<libc::sock_filter>, nr: libc::c_long, idx: u32, mask: u32,
+	deny: u32) {
+		program.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, nr as u32, 0, 4));
+		program.push(load(arg(idx)));
+		program.push(jump(libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K, mask, 1, 0));
+		program.push(ret(libc::SECCOMP_RET_ALLOW));
+		program.push(ret(deny));
+	}
+
+	/* Allows nr when all of the bits in mask are set in an argument */
+	fn allow_with(program: &mut Vec<libc::sock_filter>, nr: libc::c_long, idx: u32, mask: u32,
+	deny: u32) {
+		program.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, nr as u32, 0, 4));
+		program.push(load(arg(idx)));
+		program.push(jump(libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K, mask, 0, 1));
+		program.push(ret(libc::SECCOMP_RET_ALLOW));
+		program.push(ret(deny));
+	}
+
+	/* Allows nr when an argument has one of the values */
+	fn allow_values(program: &mut Vec<libc::sock_filter>, nr: libc::c_long, idx: u32,
+	values: &[u32], deny: u32) {
+		let count = values.len() as u8;
+		program.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, nr as u32, 0, count + 3));
+		program.push(load(arg(idx)));
+		for (idx, value) in values.iter().enumerate() {
+			program.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, *value, count - idx as u8, 0));
+		}
+		program.push(ret(deny));
+		program.push(ret(libc::SECCOMP_RET_ALLOW));
+	}
+
+	fn errno(program: &mut Vec<libc::sock_filter>, nr: libc::c_long, errno: i32) {
+		program.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, nr as u32, 0, 1));
+		program.push(ret(libc::SECCOMP_RET_ERRNO | errno as u32));
+	}
+
+	fn deny(mode: Mode) -> u32 {
+		match mode {
+			Mode::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+			Mode::Log => libc::SECCOMP_RET_LOG,
+		}
+	}
+
+	/* Anything but the native system calls is killed */
+	fn head() -> Vec<libc::sock_filter> {
+		vec![
+			load(ARCH),
+			jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, AUDIT_ARCH, 1, 0),
+			ret(libc::SECCOMP_RET_KILL_PROCESS),
+			load(NR),
+		]
+	}
+
+	pub fn reaper(mode: Mode) -> Vec<libc::sock_filter> {
+		let mut program = head();
+		for nr in REAPER {
+			allow(&mut program, *nr);
+		}
+		program.push(ret(deny(mode)));
+		program
+	}
+
+	pub fn program(promises: &str, mode: Mode) -> Result<Vec<libc::sock_filter>, Error> {
+		let deny = deny(mode);
+		let mut rpath = false;
+		let mut inet = false;
+		let mut unix = false;
+		let mut unveil = false;
+		for promise in promises.split_whitespace() {
+			match promise {
+				"stdio" | "sendfd" | "recvfd" => {}
+				"rpath" => rpath = true,
+				"inet" => inet = true,
+				"unix" => unix = true,
+				"unveil" => unveil = true,
+				promise => return Err(Error::Promise(promise.to_string())),
+			}
+		}
+
+		let mut program = head();
+		for nr in STDIO {
+			allow(&mut program, *nr);
+		}
+		/* What the standard library and tokio make descriptors
+		 * non-blocking and close-on-exec with
+		 */
+		let ioctl = [libc::FIONBIO as u32, libc::FIOCLEX as u32];
+		allow_values(&mut program, libc::SYS_ioctl, 1, &ioctl, deny);
+		/* Only new pairs of unix sockets, nothing is bound or
+		 * connected to by path
+		 */
+		if unix {
+			allow_values(&mut program, libc::SYS_socketpair, 0, &[libc::AF_UNIX as u32], deny);
+		}
+		/* Accepting on sockets that were bound for it */
+		if inet || unix {
+			allow(&mut program, libc::SYS_accept4);
+			#[cfg(target_arch = "x86_64")]
+			allow(&mut program, libc::SYS_accept);
+		}
+		/* Threads for blocking work but no new processes, glibc
+		 * falls back to clone() when clone3() is missing, whose
+		 * flags can be looked at
+		 */
+		errno(&mut program, libc::SYS_clone3, libc::ENOSYS);
+		allow_with(&mut program, libc::SYS_clone, 0, libc::CLONE_THREAD as u32, deny);
+		/* Naming threads, and a later pledge() stacking another filter,
+		 * which can only take away more
+		 */
+		let prctl = [libc::PR_SET_NAME as u32, libc::PR_SET_NO_NEW_PRIVS as u32];
+		allow_values(&mut program, libc::SYS_prctl, 0, &prctl, deny);
+		allow(&mut program, libc::SYS_seccomp);
+		/* Landlock is given the unveiled paths by opening them */
+		if unveil {
+			allow(&mut program, libc::SYS_landlock_create_ruleset);
+			allow(&mut program, libc::SYS_landlock_add_rule);
+			allow(&mut program, libc::SYS_landlock_restrict_self);
+		}
+		if rpath || unveil {
+			for nr in RPATH {
+				allow(&mut program, *nr);
+			}
+			let write = libc::O_WRONLY | libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC;
+			allow_without(&mut program, libc::SYS_openat, 2, write as u32, deny);
+		}
+		else {
+			/* The standard library uses fstat() instead */
+			errno(&mut program, libc::SYS_statx, libc::ENOSYS);
+			/* glibc's malloc looks up the number of CPUs in /sys
+			 * when a thread needs another arena, and does without
+			 */
+			errno(&mut program, libc::SYS_openat, libc::EACCES);
+		}
+		program.push(ret(deny));
+		Ok(program)
+	}
+
+	pub fn install(program: &[libc::sock_filter]) -> std::io::Result<()> {
+		let prog = libc::sock_fprog {
+			len: program.len() as u16,
+			filter: program.as_ptr() as *mut libc::sock_filter,
+		};
+		if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+			return Err(std::io::Error::last_os_error());
+		}
+		/* Threads tokio already started are covered as well */
+		let ret = unsafe {
+			libc::syscall(libc::SYS_seccomp, libc::SECCOMP_SET_MODE_FILTER,
+				libc::SECCOMP_FILTER_FLAG_TSYNC, &prog)
+		};
+		match ret {
+			0 => Ok(()),
+			-1 => Err(std::io::Error::last_os_error()),
+			_ => Err(std::io::Error::other("threads could not be synchronized")),
+		}
+	}
+}
+
+/* Filters stack, like pledge() a later call can only take away */
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn install(promises: &str) -> Result<(), Error> {
+	let program = filter::program(promises, mode())?;
+	filter::install(&program).map_err(Error::Install)
+}
+
+/* For the reaper, which promises less than stdio */
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn reaper() -> Result<(), Error> {
+	filter::install(&filter::reaper(mode())).map_err(Error::Install)
+}
+
+#[cfg(all(target_os = "linux", not(any(target_arch = "x86_64", target_arch = "aarch64"))))]
+pub fn install(_: &str) -> Result<(), Error> {
+	crate::log::warning!("seccomp is not supported on this architecture, promises are not enforced");
+	Ok(())
+}
+
+#[cfg(all(target_os = "linux", not(any(target_arch = "x86_64", target_arch = "aarch64"))))]
+pub fn reaper() -> Result<(), Error> {
+	install("")
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn install(_: &str) -> Result<(), Error> {
+	Ok(())
+}
+
+#[cfg(all(test, target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod tests {
+	use super::*;
+	use nix::libc;
+	#[test]
+	fn program() {
+		let program = filter::program("stdio rpath sendfd recvfd unveil", Mode::Kill).unwrap();
+		/* Every jump lands inside the program, which ends denying */
+		for (idx, insn) in program.iter().enumerate() {
+			if u32::from(insn.code) & 0x07 == libc::BPF_JMP {
+				assert!(idx + 1 + usize::from(insn.jt.max(insn.jf)) < program.len());
+			}
+		}
+		let last = program.last().unwrap();
+		assert_eq!(u32::from(last.code), libc::BPF_RET | libc::BPF_K);
+		assert_eq!(last.k, libc::SECCOMP_RET_KILL_PROCESS);
+
+		let program = filter::program("stdio", Mode::Log).unwrap();
+		assert_eq!(program.last().unwrap().k, libc::SECCOMP_RET_LOG);
+		assert_eq!(filter::reaper(Mode::Kill).last().unwrap().k, libc::SECCOMP_RET_KILL_PROCESS);
+
+		assert!(matches!(filter::program("stdio dns", Mode::Kill), Err(Error::Promise(_))));
+
+		/* socketpair() is only there for the unix promise */
+		let allows = |promises: &str, nr: libc::c_long| {
+			filter::program(promises, Mode::Kill).unwrap().iter()
+				.any(|insn| u32::from(insn.code) & 0x07 == libc::BPF_JMP && insn.k == nr as u32)
+		};
+		assert!(!allows("stdio recvfd", libc::SYS_socketpair));
+		assert!(allows("stdio recvfd sendfd unix", libc::SYS_socketpair));
+		assert!(!allows("stdio recvfd sendfd unix", libc::SYS_bind));
+		assert!(allows("stdio inet", libc::SYS_accept4));
+	}
+}
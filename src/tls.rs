@@ -1,19 +1,86 @@
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
 use rustls_pemfile::certs;
+use std::collections::HashMap;
 use std::io::BufReader;
+use std::sync::Arc;
 
-pub fn certs_from_file(file: std::fs::File) -> Result<Vec<CertificateDer<'static>>, std::io::Error> {
+pub fn certs_from_file(file: &std::fs::File) -> Result<Vec<CertificateDer<'static>>, std::io::Error> {
     let mut reader = BufReader::new(file);
     let certs = certs(&mut reader);
 	let certs: Vec<_> = certs.collect::<Result<Vec<CertificateDer>, _>>()?;
     Ok(certs)
 }
 
-pub fn keys_from_file(file: std::fs::File) -> Result<PrivateKeyDer<'static>, std::io::Error> {
+pub fn keys_from_file(file: &std::fs::File) -> Result<PrivateKeyDer<'static>, std::io::Error> {
 	let mut reader = BufReader::new(file);
 	let mut keys = rustls_pemfile::pkcs8_private_keys(&mut reader);
 	
 	let key = keys.next()
-		.ok_or::<std::io::Error>(std::io::ErrorKind::NotFound.into())??;
+		.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "no PKCS #8 key"))??;
 	Ok(key.into())
 }
+
+/* A certificate chain and a key rustls can sign with, read from the
+ * files of a server
+ */
+pub fn certified_key(cert: &std::fs::File, key: &std::fs::File)
+-> Result<Arc<CertifiedKey>, std::io::Error> {
+	let cert = certs_from_file(cert)?;
+	if cert.is_empty() {
+		return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "no certificate"));
+	}
+	let key = keys_from_file(key)?;
+	let key = rustls::crypto::ring::sign::any_supported_type(&key)
+		.map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+	Ok(Arc::new(CertifiedKey::new(cert, key)))
+}
+
+/* The certificate of every server, in order, and by the names it
+ * answers to
+ */
+#[derive(Debug, Default)]
+pub struct Certificates {
+	keys: Vec<Arc<CertifiedKey>>,
+	names: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl Certificates {
+	pub fn add(&mut self, names: &[String], key: Arc<CertifiedKey>) {
+		for name in names {
+			self.names.insert(name.to_ascii_lowercase(), key.clone());
+		}
+		self.keys.push(key);
+	}
+
+	/* A resolver for each listener, given the server each one
+	 * defaults to
+	 */
+	pub fn resolvers(self, defaults: &[Option<usize>]) -> Vec<Resolver> {
+		let names = Arc::new(self.names);
+		defaults.iter().map(|default| Resolver {
+			default: default.and_then(|idx| self.keys.get(idx)).cloned(),
+			names: names.clone(),
+		}).collect()
+	}
+}
+
+/* Picks the certificate by SNI, falling back to the certificate of
+ * the listener's default server when the client sent no name or an
+ * unknown one
+ */
+#[derive(Debug)]
+pub struct Resolver {
+	default: Option<Arc<CertifiedKey>>,
+	names: Arc<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl ResolvesServerCert for Resolver {
+	fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+		hello.server_name()
+			.and_then(|name| self.names.get(&name.to_ascii_lowercase()))
+			.or(self.default.as_ref())
+			.cloned()
+	}
+}
@@ -0,0 +1,101 @@
+/* Runs the whole server without root: the parent, a client and the
+ * filesystem process answering requests over a real socket
+ */
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+
+struct Server(Child);
+
+impl Drop for Server {
+	fn drop(&mut self) {
+		let pid = nix::unistd::Pid::from_raw(self.0.id() as i32);
+		let _ = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGTERM);
+		let _ = self.0.wait();
+	}
+}
+
+fn get(port: u16, path: &str) -> String {
+	let deadline = Instant::now() + Duration::from_secs(10);
+	let mut stream = loop {
+		match TcpStream::connect(("127.0.0.1", port)) {
+			Ok(stream) => break stream,
+			Err(_) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(50)),
+			Err(err) => panic!("connect: {err}"),
+		}
+	};
+	write!(stream, "GET {path} HTTP/1.0\r\nHost: localhost\r\n\r\n").unwrap();
+	let mut response = String::new();
+	stream.read_to_string(&mut response).unwrap();
+	response
+}
+
+#[test]
+fn unprivileged() {
+	let dir = std::env::temp_dir().join(format!("httpd-unprivileged-{}", std::process::id()));
+	let htdocs = dir.join("chroot/htdocs");
+	std::fs::create_dir_all(&htdocs).unwrap();
+	std::fs::write(htdocs.join("index.html"), "hello").unwrap();
+	std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+	std::os::unix::fs::symlink(dir.join("secret.txt"), htdocs.join("link")).unwrap();
+
+	/* Another test may take the port in between, unlikely enough */
+	let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+	let types = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/mime.types");
+	let config = dir.join("httpd.conf");
+	std::fs::write(&config, format!("types \"{}\"\nchroot \"{}\"\nprefork 1\n\
+		server \"localhost\" {{\n\tlisten on 127.0.0.1 port {port}\n}}\n",
+		types.display(), dir.join("chroot").display())).unwrap();
+
+	let server = Server(Command::new(env!("CARGO_BIN_EXE_httpd"))
+		.arg("-dU").arg("-f").arg(&config)
+		.spawn().unwrap());
+
+	let response = get(port, "/index.html");
+	assert!(response.starts_with("HTTP/1.0 200") || response.starts_with("HTTP/1.1 200"),
+		"{response}");
+	assert!(response.ends_with("hello"));
+
+	let response = get(port, "/link");
+	assert!(!response.contains("secret"), "{response}");
+
+	drop(server);
+	std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/* httpd -p ctl, on a control socket the test can bind */
+#[test]
+fn control() {
+	let dir = std::env::temp_dir().join(format!("httpd-control-{}", std::process::id()));
+	let htdocs = dir.join("chroot/htdocs");
+	std::fs::create_dir_all(&htdocs).unwrap();
+	std::fs::write(htdocs.join("index.html"), "hello").unwrap();
+
+	let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+	let types = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/mime.types");
+	let config = dir.join("httpd.conf");
+	std::fs::write(&config, format!("types \"{}\"\nchroot \"{}\"\nprefork 1\n\
+		control socket \"{}\"\n\
+		server \"localhost\" {{\n\tlisten on 127.0.0.1 port {port}\n}}\n",
+		types.display(), dir.join("chroot").display(), dir.join("httpd.sock").display())).unwrap();
+
+	let server = Server(Command::new(env!("CARGO_BIN_EXE_httpd"))
+		.arg("-dU").arg("-f").arg(&config)
+		.spawn().unwrap());
+
+	/* Listening means the control socket is there as well */
+	let response = get(port, "/index.html");
+	assert!(response.ends_with("hello"), "{response}");
+
+	let status = Command::new(env!("CARGO_BIN_EXE_httpd"))
+		.arg("-f").arg(&config).args(["-p", "ctl", "status"])
+		.output().unwrap();
+	let stdout = String::from_utf8_lossy(&status.stdout);
+	assert!(status.status.success(), "{}", String::from_utf8_lossy(&status.stderr));
+	assert!(stdout.contains("filesystem pid") && stdout.contains("client 0 pid"), "{stdout}");
+
+	drop(server);
+	std::fs::remove_dir_all(&dir).unwrap();
+}